@@ -0,0 +1,87 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// gzip (RFC 1952) and zlib (RFC 1950), through flate2.
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use std::io::Write;
+
+use crate::Result;
+
+pub fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(
+        Vec::with_capacity(data.len() / 2 + 32),
+        Compression::default(),
+    );
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+pub fn zlib(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(
+        Vec::with_capacity(data.len() / 2 + 32),
+        Compression::default(),
+    );
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_records_decode_to_the_input() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+        use std::io::Read;
+
+        let record = br#"{"message":"hello","priority":"6","systemd-unit":"app.service"}"#;
+        let data: Vec<u8> = record
+            .iter()
+            .cycle()
+            .take(record.len() * 20)
+            .cloned()
+            .collect();
+
+        let compressed = gzip(&data).unwrap();
+        assert_eq!(compressed[..3], [0x1f, 0x8b, 8]);
+        assert!(compressed.len() < data.len() / 4);
+        let mut decoded = vec![];
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let compressed = zlib(&data).unwrap();
+        assert_eq!(
+            (u32::from(compressed[0]) << 8 | u32::from(compressed[1])) % 31,
+            0
+        );
+        let mut decoded = vec![];
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let mut decoded = vec![];
+        GzDecoder::new(&gzip(b"").unwrap()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert!(decoded.is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use serde_yaml::{from_str as yaml_from_str, to_writer as yaml_to_writer};
+
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::mpsc,
+    time::{Duration as StdDuration, Instant as StdInstant},
+};
+
+use crate::Result;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct CursorRecord {
+    pub position: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub namespace: String,
+}
+
+// Saved instead of a cursor when reading should start at the head of the journal
+pub const HEAD_POSITION: &str = "head";
+
+// The default namespace keeps using `position` so older cursor files still load
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct CursorState {
+    pub position: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub namespaces: BTreeMap<String, String>,
+}
+
+impl CursorState {
+    pub fn get(&self, namespace: &str) -> &str {
+        if namespace.is_empty() {
+            &self.position
+        } else {
+            self.namespaces
+                .get(namespace)
+                .map(String::as_str)
+                .unwrap_or_default()
+        }
+    }
+
+    pub fn update(&mut self, record: CursorRecord) {
+        if record.position.is_empty() {
+            return;
+        }
+        if record.namespace.is_empty() {
+            self.position = record.position;
+        } else {
+            self.namespaces.insert(record.namespace, record.position);
+        }
+    }
+
+    pub fn records(&self) -> Vec<CursorRecord> {
+        let default_record = CursorRecord {
+            position: self.position.clone(),
+            namespace: String::default(),
+        };
+
+        std::iter::once(default_record)
+            .chain(
+                self.namespaces
+                    .iter()
+                    .map(|(namespace, position)| CursorRecord {
+                        position: position.clone(),
+                        namespace: namespace.clone(),
+                    }),
+            )
+            .filter(|record| !record.position.is_empty())
+            .collect()
+    }
+}
+
+pub fn read_cursor_state(path: &str) -> CursorState {
+    let mut yaml_string = String::default();
+    if let Ok(mut cursor_file) = OpenOptions::new().read(true).open(path) {
+        cursor_file
+            .read_to_string(&mut yaml_string)
+            .unwrap_or_default();
+    }
+    yaml_from_str(&yaml_string).unwrap_or_default()
+}
+
+pub fn write_cursor_state(path: &str, cursor_state: &CursorState) -> Result<()> {
+    let mut cursor_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|error| {
+            failure::format_err!("{:#?}\nwhile trying to open file: {}", error, path)
+        })?;
+    cursor_file.seek(SeekFrom::Start(0))?;
+    cursor_file.set_len(0)?;
+    yaml_to_writer(&cursor_file, cursor_state)?;
+    cursor_file.write_all(b"\n")?;
+    Ok(())
+}
+
+pub fn read_write_cursor_thread(
+    path: &str,
+    initial_cursor_state: CursorState,
+    cursor_receiver: &mpsc::Receiver<CursorRecord>,
+) {
+    let mut pit = StdInstant::now();
+    let mut written_cursor_state = CursorState::default();
+    let mut local_cursor_state = initial_cursor_state;
+    // Open cursor file
+    while let Ok(local_cursor_value) = cursor_receiver.recv() {
+        local_cursor_state.update(local_cursor_value);
+        if pit.elapsed() > StdDuration::from_millis(1234)
+            && written_cursor_state != local_cursor_state
+        {
+            write_cursor_state(path, &local_cursor_state)
+                .unwrap_or_else(|error| panic!("{}", error));
+            pit = StdInstant::now();
+            written_cursor_state = local_cursor_state.clone();
+        }
+    }
+
+    // Every sink is gone, keep whatever was acknowledged since the last write
+    if written_cursor_state != local_cursor_state {
+        write_cursor_state(path, &local_cursor_state).unwrap_or_else(|error| panic!("{}", error));
+    }
+}
+
+// Get a single key=value component, such as the t= realtime, out of a cursor
+pub fn cursor_field(position: &str, key: &str) -> Option<String> {
+    position
+        .split(';')
+        .filter_map(|field| {
+            let mut key_value = field.splitn(2, '=');
+            Some((key_value.next()?, key_value.next()?))
+        })
+        .find(|(field_key, _)| *field_key == key)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_field_reads_components() {
+        let cursor = "s=abc;i=8;b=def;m=37b75020;t=65e1c7e7fe589;x=5fe6";
+        assert_eq!(
+            cursor_field(cursor, "t"),
+            Some(String::from("65e1c7e7fe589"))
+        );
+        assert_eq!(cursor_field(cursor, "s"), Some(String::from("abc")));
+        assert_eq!(cursor_field(cursor, "q"), None);
+        assert_eq!(cursor_field("garbage", "t"), None);
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use serde_json::Value as JsonValue;
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    sync::mpsc,
+};
+
+use crate::{
+    cursor::CursorRecord,
+    journal::record_event,
+    pipeline::{forward, Event, Source, Transform},
+    settings::Settings,
+    Result,
+};
+
+// Data that is not UTF-8 is kept base64 encoded under the field name with
+// this suffix, which no journal field name can have
+pub const BINARY_SUFFIX: &str = ".base64";
+
+// The longest binary field that is read, as systemd-journal-remote allows.
+// The data is read as it arrives, so a length that lies costs no memory.
+pub const MAX_FIELD_BYTES: u64 = 768 * 1024 * 1024;
+
+// Upper case letters, digits and underscores, as journald allows them
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit() || byte == b'_')
+}
+
+fn insert_field(entry: &mut BTreeMap<String, String>, key: &[u8], data: Vec<u8>) -> Result<()> {
+    let key = String::from_utf8_lossy(key).to_string();
+    if !is_field_name(&key) {
+        failure::bail!("{:?} is not a journal field name", key);
+    }
+    match String::from_utf8(data) {
+        Ok(value) => entry.insert(key, value),
+        Err(error) => entry.insert(
+            format!("{}{}", key, BINARY_SUFFIX),
+            BASE64.encode(error.into_bytes()),
+        ),
+    };
+    Ok(())
+}
+
+// Read one entry of the Journal Export Format, None at the end of the input.
+// A field is a KEY=value line, or for data that is binary or holds a newline
+// the name on its own line followed by a little endian 64 bit length, the
+// data and a newline. An empty line ends the entry.
+pub fn read_export_entry<R: BufRead>(reader: &mut R) -> Result<Option<BTreeMap<String, String>>> {
+    let mut entry = BTreeMap::new();
+
+    loop {
+        let mut line = vec![];
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if line == b"\n" {
+            if entry.is_empty() {
+                continue;
+            }
+            break;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+
+        match line.iter().position(|&byte| byte == b'=') {
+            Some(split) => insert_field(&mut entry, &line[..split], line[split + 1..].to_vec())?,
+            None => {
+                let mut length = [0u8; 8];
+                reader.read_exact(&mut length)?;
+                let length = u64::from_le_bytes(length);
+                if length > MAX_FIELD_BYTES {
+                    failure::bail!(
+                        "The binary field {} claims {} bytes, more than {}",
+                        String::from_utf8_lossy(&line),
+                        length,
+                        MAX_FIELD_BYTES
+                    );
+                }
+                let mut data = vec![];
+                reader.by_ref().take(length).read_to_end(&mut data)?;
+                if data.len() as u64 != length {
+                    failure::bail!(
+                        "The binary field {} ends after {} of {} bytes",
+                        String::from_utf8_lossy(&line),
+                        data.len(),
+                        length
+                    );
+                }
+                let mut newline = [0u8; 1];
+                reader.read_exact(&mut newline)?;
+                if newline != *b"\n" {
+                    failure::bail!(
+                        "The binary field {} is not followed by a newline",
+                        String::from_utf8_lossy(&line)
+                    );
+                }
+                insert_field(&mut entry, &line, data)?;
+            }
+        }
+    }
+
+    if entry.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(entry))
+    }
+}
+
+// Append one entry in the Journal Export Format, the fields that are not
+// plain printable text are written in the binary form, base64 encoded ones
+// as the bytes they were read as. The address fields come first, as
+// journalctl writes them.
+pub fn write_export_entry(fields: &BTreeMap<String, String>, out: &mut Vec<u8>) {
+    let address = fields.iter().filter(|(key, _)| key.starts_with("__"));
+    let data = fields.iter().filter(|(key, _)| !key.starts_with("__"));
+    for (key, value) in address.chain(data) {
+        let binary = key
+            .strip_suffix(BINARY_SUFFIX)
+            .and_then(|name| Some((name, BASE64.decode(value).ok()?)));
+        if let Some((name, bytes)) = binary {
+            out.extend_from_slice(name.as_bytes());
+            out.push(b'\n');
+            out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            out.extend_from_slice(&bytes);
+        } else if value.bytes().any(|byte| byte < b' ' && byte != b'\t') {
+            out.extend_from_slice(key.as_bytes());
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            out.extend_from_slice(value.as_bytes());
+        } else {
+            out.extend_from_slice(key.as_bytes());
+            out.push(b'=');
+            out.extend_from_slice(value.as_bytes());
+        }
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+}
+
+// The journal fields of a record, made up from its JSON for a synthetic
+// record such as the gap, where journald.gap becomes JOURNALD_GAP
+pub fn export_fields(event: &Event) -> BTreeMap<String, String> {
+    if !event.fields.is_empty() {
+        return event.fields.clone();
+    }
+
+    let mut fields = BTreeMap::new();
+    if let Some(object) = event.value.as_object() {
+        for (key, value) in object.iter() {
+            let name: String = key
+                .chars()
+                .map(|character| match character {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' => character.to_ascii_uppercase(),
+                    _ => '_',
+                })
+                .collect();
+            let name = name.trim_start_matches(|character: char| !character.is_ascii_alphabetic());
+            let value = match value {
+                JsonValue::String(text) => text.clone(),
+                other => other.to_string(),
+            };
+            if !name.is_empty() {
+                fields.insert(name.to_string(), value);
+            }
+        }
+    }
+    fields
+}
+
+// The __REALTIME_TIMESTAMP of an entry, in microseconds
+pub fn export_realtime(entry: &BTreeMap<String, String>) -> Option<u64> {
+    entry
+        .get("__REALTIME_TIMESTAMP")
+        .and_then(|realtime| realtime.parse::<u64>().ok())
+}
+
+pub fn usec_to_datetime(usec: u64) -> DateTime<Utc> {
+    Utc.timestamp((usec / 1_000_000) as i64, (usec % 1_000_000) as u32 * 1_000)
+}
+
+// The __CURSOR of an entry, or one made up from its boot and timestamps the
+// way journald builds them when the export has no cursors
+pub fn export_cursor(entry: &BTreeMap<String, String>) -> String {
+    if let Some(cursor) = entry.get("__CURSOR") {
+        return cursor.clone();
+    }
+
+    let hex = |key: &str| {
+        entry
+            .get(key)
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(|value| format!("{:x}", value))
+    };
+    let mut fields = vec![];
+    if let Some(boot_id) = entry.get("_BOOT_ID") {
+        fields.push(format!("b={}", boot_id));
+    }
+    if let Some(monotonic) = hex("__MONOTONIC_TIMESTAMP") {
+        fields.push(format!("m={}", monotonic));
+    }
+    if let Some(realtime) = hex("__REALTIME_TIMESTAMP") {
+        fields.push(format!("t={}", realtime));
+    }
+    fields.join(";")
+}
+
+// An export entry mapped like a record read from the journal
+pub fn export_event(entry: &BTreeMap<String, String>, cursor: CursorRecord) -> Event {
+    let timestamp = export_realtime(entry)
+        .map(usec_to_datetime)
+        .unwrap_or_else(Utc::now);
+
+    record_event(entry.clone(), timestamp, cursor)
+}
+
+// Reads a Journal Export Format stream to its end, such as the output of
+// journalctl -o export or the upload stream of systemd-journal-remote
+pub struct ExportReader<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> ExportReader<R> {
+    pub fn new(reader: R) -> ExportReader<R> {
+        ExportReader { reader }
+    }
+}
+
+impl<R: BufRead> Source for ExportReader<R> {
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        let entry = match read_export_entry(&mut self.reader)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let cursor = CursorRecord {
+            position: export_cursor(&entry),
+            namespace: String::default(),
+        };
+
+        Ok(Some(export_event(&entry, cursor)))
+    }
+}
+
+// Forward all of journal-export, - being standard input, returning the
+// number of records handed to the sender
+pub fn forward_export(
+    settings: &Settings,
+    transforms: &mut [Box<dyn Transform>],
+    sender: &mpsc::SyncSender<Event>,
+) -> Result<u64> {
+    let path = &settings.journal_export;
+
+    if path == "-" {
+        let stdin = std::io::stdin();
+        let mut reader = ExportReader::new(stdin.lock());
+        forward(&mut reader, transforms, sender)
+    } else {
+        let file = File::open(path).map_err(|error| {
+            failure::format_err!("{:#?}\nwhile trying to open file: {}", error, path)
+        })?;
+        let mut reader = ExportReader::new(BufReader::new(file));
+        forward(&mut reader, transforms, sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_and_binary_fields_are_read() {
+        let mut input: Vec<u8> = b"__REALTIME_TIMESTAMP=1000001\nMESSAGE\n".to_vec();
+        input.extend_from_slice(&11u64.to_le_bytes());
+        input.extend_from_slice(b"two\nlines=x\n\n\nMESSAGE=second\n");
+        let mut reader = std::io::Cursor::new(input);
+
+        let first = read_export_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(first["MESSAGE"], "two\nlines=x");
+        assert_eq!(export_realtime(&first), Some(1_000_001));
+        assert_eq!(
+            usec_to_datetime(1_000_001).to_rfc3339(),
+            "1970-01-01T00:00:01.000001+00:00"
+        );
+
+        let second = read_export_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(second["MESSAGE"], "second");
+        assert_eq!(read_export_entry(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn data_that_is_not_utf8_is_kept_intact() {
+        let mut input: Vec<u8> = b"MESSAGE=ok\nCOREDUMP\n".to_vec();
+        input.extend_from_slice(&4u64.to_le_bytes());
+        input.extend_from_slice(b"\xff\x00\xfe\n\n\n");
+        input.extend_from_slice(b"USER_DATA=caf\xe9\n\n");
+        let mut reader = std::io::Cursor::new(input.clone());
+
+        let first = read_export_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(first["COREDUMP.base64"], "/wD+Cg==");
+        assert!(!first.contains_key("COREDUMP"));
+        let second = read_export_entry(&mut reader).unwrap().unwrap();
+        assert_eq!(second["USER_DATA.base64"], "Y2Fm6Q==");
+
+        let mut out = vec![];
+        write_export_entry(&first, &mut out);
+        write_export_entry(&second, &mut out);
+        let mut expected = b"COREDUMP\n".to_vec();
+        expected.extend_from_slice(&4u64.to_le_bytes());
+        expected.extend_from_slice(b"\xff\x00\xfe\n\nMESSAGE=ok\n\nUSER_DATA\n");
+        expected.extend_from_slice(&4u64.to_le_bytes());
+        expected.extend_from_slice(b"caf\xe9\n\n");
+        assert_eq!(out, expected);
+
+        let mut spoofed = std::io::Cursor::new(b"COREDUMP.base64=/wD+Cg==\n\n".to_vec());
+        assert!(read_export_entry(&mut spoofed).is_err());
+    }
+
+    #[test]
+    fn a_binary_length_that_lies_is_refused() {
+        let mut huge: Vec<u8> = b"COREDUMP\n".to_vec();
+        huge.extend_from_slice(&u64::MAX.to_le_bytes());
+        huge.extend_from_slice(b"\xff\x00\n\n");
+        let error = read_export_entry(&mut std::io::Cursor::new(huge)).unwrap_err();
+        assert!(error.to_string().contains("more than"), "{}", error);
+
+        let mut short: Vec<u8> = b"COREDUMP\n".to_vec();
+        short.extend_from_slice(&MAX_FIELD_BYTES.to_le_bytes());
+        short.extend_from_slice(b"\xff\x00\n\n");
+        let error = read_export_entry(&mut std::io::Cursor::new(short)).unwrap_err();
+        assert!(error.to_string().contains("ends after 4 of"), "{}", error);
+    }
+
+    #[test]
+    fn exported_cursors_are_kept_or_made_up() {
+        let export = "__CURSOR=s=1;i=2\n__REALTIME_TIMESTAMP=16\nMESSAGE=first\n\n\
+                      __REALTIME_TIMESTAMP=32\n__MONOTONIC_TIMESTAMP=255\n\
+                      _BOOT_ID=abc\nMESSAGE=second\n";
+        let mut reader = ExportReader::new(export.as_bytes());
+
+        let first = reader.next_event().unwrap().unwrap();
+        assert_eq!(first.cursor.position, "s=1;i=2");
+        assert_eq!(first.value["journald.cursor"], "s=1;i=2");
+        assert_eq!(first.value["@timestamp"], "1970-01-01T00:00:00.000016Z");
+        assert_eq!(first.value["message"], "first");
+        assert!(first.value.get("cursor").is_none());
+
+        let second = reader.next_event().unwrap().unwrap();
+        assert_eq!(second.cursor.position, "b=abc;m=ff;t=20");
+        assert_eq!(second.value["boot-id"], "abc");
+        assert_eq!(reader.next_event().unwrap(), None);
+    }
+    #[test]
+    fn written_entries_read_back_the_same() {
+        let mut fields = BTreeMap::new();
+        fields.insert(String::from("__CURSOR"), String::from("s=1;i=2"));
+        fields.insert(String::from("MESSAGE"), String::from("two\nlines"));
+        fields.insert(String::from("PRIORITY"), String::from("6"));
+        let mut out = vec![];
+        write_export_entry(&fields, &mut out);
+        write_export_entry(&fields, &mut out);
+
+        let mut reader = std::io::Cursor::new(out);
+        assert_eq!(
+            read_export_entry(&mut reader).unwrap(),
+            Some(fields.clone())
+        );
+        assert_eq!(read_export_entry(&mut reader).unwrap(), Some(fields));
+        assert_eq!(read_export_entry(&mut reader).unwrap(), None);
+
+        let gap = Event {
+            value: serde_json::json!({ "journald.gap": true, "message": "lost" }),
+            cursor: CursorRecord::default(),
+            fields: BTreeMap::new(),
+        };
+        let made_up = export_fields(&gap);
+        assert_eq!(made_up["JOURNALD_GAP"], "true");
+        assert_eq!(made_up["MESSAGE"], "lost");
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// The Fluentd Forward protocol v1, as spoken by fluentd and fluent-bit on
+// 24224. Records go in PackedForward messages, one per tag, and with
+// require_ack_response a batch is only acknowledged once every chunk id has
+// come back.
+
+use chrono::Utc;
+
+use rmp::encode::{write_array_len, write_bin, write_map_len, write_str, write_uint};
+
+use serde_json::Value as JsonValue;
+
+use sha2::{Digest, Sha512};
+
+use std::{collections::BTreeMap, fs::File, io::Read};
+
+use crate::{
+    cursor::CursorRecord,
+    msgpack::{read_value, write_event_time, write_json, MsgValue},
+    pipeline::{Event, Sink},
+    sink::{HostRecord, RemoteConnection},
+    Result,
+};
+
+// The handshake digests are SHA-512 in lowercase hex of the parts joined
+pub fn sha512_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn random_hex(length: usize) -> Result<String> {
+    let mut bytes = vec![0u8; length];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn self_hostname() -> String {
+    let mut buffer = [0u8; 256];
+    nix::unistd::gethostname(&mut buffer)
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| String::from("localhost"))
+}
+
+// Fill in a tag template such as journal.{_SYSTEMD_UNIT} from the journal
+// fields of a record, or its JSON keys for a synthetic record
+pub fn fluent_tag(template: &str, event: &Event) -> String {
+    let mut tag = String::default();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        tag.push_str(&rest[..start]);
+        let name = &rest[start + 1..end];
+        let value = event
+            .fields
+            .get(name)
+            .map(String::as_str)
+            .or_else(|| event.value.get(name).and_then(JsonValue::as_str))
+            .unwrap_or("unknown");
+        tag.push_str(value);
+        rest = &rest[end + 1..];
+    }
+
+    tag.push_str(rest);
+    tag
+}
+
+// The EventTime of a record, now when it has no timestamp
+fn event_time(event: &Event) -> (u32, u32) {
+    let timestamp = event.timestamp().unwrap_or_else(Utc::now);
+    (
+        timestamp.timestamp() as u32,
+        timestamp.timestamp_subsec_nanos(),
+    )
+}
+
+// One PackedForward message, [tag, entries, option]
+fn packed_forward(tag: &str, events: &[&Event], chunk: Option<&str>) -> Result<Vec<u8>> {
+    let mut entries = vec![];
+    for event in events {
+        write_array_len(&mut entries, 2)?;
+        let (seconds, nanoseconds) = event_time(event);
+        write_event_time(&mut entries, seconds, nanoseconds)?;
+        write_json(&mut entries, &event.value)?;
+    }
+
+    let mut message = vec![];
+    write_array_len(&mut message, 3)?;
+    write_str(&mut message, tag)?;
+    write_bin(&mut message, &entries)?;
+    write_map_len(&mut message, if chunk.is_some() { 2 } else { 1 })?;
+    write_str(&mut message, "size")?;
+    write_uint(&mut message, events.len() as u64)?;
+    if let Some(chunk) = chunk {
+        write_str(&mut message, "chunk")?;
+        write_str(&mut message, chunk)?;
+    }
+    Ok(message)
+}
+
+pub struct FluentForwardSink {
+    connection: RemoteConnection,
+    tag: String,
+    require_ack: bool,
+    shared_key: String,
+    authenticated: bool,
+    last_ack: String,
+}
+
+impl FluentForwardSink {
+    pub fn new(
+        host: HostRecord,
+        tag: &str,
+        require_ack: bool,
+        shared_key: &str,
+    ) -> FluentForwardSink {
+        FluentForwardSink {
+            connection: RemoteConnection::new(host),
+            tag: tag.to_string(),
+            require_ack,
+            shared_key: shared_key.to_string(),
+            authenticated: false,
+            last_ack: String::default(),
+        }
+    }
+
+    // The records of a batch by tag, keeping their order within each tag
+    fn messages<'a>(&self, batch: &'a [Event]) -> BTreeMap<String, Vec<&'a Event>> {
+        let mut messages: BTreeMap<String, Vec<&Event>> = BTreeMap::new();
+        for event in batch {
+            messages
+                .entry(fluent_tag(&self.tag, event))
+                .or_default()
+                .push(event);
+        }
+        messages
+    }
+
+    fn read_value(&mut self) -> Result<MsgValue> {
+        let value = self.connection.stream().and_then(read_value);
+        if value.is_err() {
+            self.connection.disconnect();
+        }
+        value
+    }
+
+    // The server opens with HELO, answered by a PING that proves we know the
+    // shared key, and closes with a PONG that proves it does as well
+    fn handshake(&mut self) -> Result<()> {
+        let helo = self.read_value()?;
+        let options = match helo.as_array().map(Vec::as_slice) {
+            Some([kind, options]) if kind.as_slice() == Some(b"HELO") => options.clone(),
+            _ => failure::bail!("Expected HELO from the server, got {:?}", helo),
+        };
+        let nonce = options["nonce"].as_slice().unwrap_or_default().to_vec();
+        if options["auth"].as_slice().map(<[u8]>::is_empty) == Some(false) {
+            failure::bail!("The server asks for a username and password, which are not supported");
+        }
+
+        let hostname = self_hostname();
+        let salt = random_hex(16)?;
+        let shared_key = self.shared_key.clone();
+        let digest = |name: &str| {
+            sha512_hex(&[
+                salt.as_bytes(),
+                name.as_bytes(),
+                &nonce,
+                shared_key.as_bytes(),
+            ])
+        };
+        let mut ping = vec![];
+        write_array_len(&mut ping, 6)?;
+        for part in ["PING", &hostname, &salt, &digest(&hostname), "", ""].iter() {
+            write_str(&mut ping, part)?;
+        }
+        self.connection.write_all(&ping)?;
+
+        let pong = self.read_value()?;
+        let result = match pong.as_array().map(Vec::as_slice) {
+            Some([kind, ok, reason, server, server_digest]) if kind.as_slice() == Some(b"PONG") => {
+                let server = String::from_utf8_lossy(server.as_slice().unwrap_or_default());
+                if *ok != MsgValue::Boolean(true) {
+                    Err(failure::format_err!(
+                        "The server refused the shared key, {}",
+                        String::from_utf8_lossy(reason.as_slice().unwrap_or_default())
+                    ))
+                } else if server_digest.as_slice() != Some(digest(&server).as_bytes()) {
+                    Err(failure::format_err!(
+                        "The server {} does not know the shared key",
+                        server
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Err(failure::format_err!(
+                "Expected PONG from the server, got {:?}",
+                pong
+            )),
+        };
+        if result.is_err() {
+            self.connection.disconnect();
+        }
+        result
+    }
+}
+
+impl Sink for FluentForwardSink {
+    fn label(&self) -> String {
+        self.connection.host.label()
+    }
+
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        for (tag, events) in self.messages(batch) {
+            bytes.extend(packed_forward(&tag, &events, None)?);
+        }
+        Ok(bytes)
+    }
+
+    // The messages of a batch that were acknowledged before a failure are
+    // sent again with the rest of it
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        if self.connection.connected.is_none() {
+            self.authenticated = false;
+        }
+        if !self.shared_key.is_empty() && !self.authenticated {
+            self.connection.stream()?;
+            self.handshake()?;
+            self.authenticated = true;
+        }
+
+        for (tag, events) in self.messages(batch) {
+            let chunk = if self.require_ack {
+                Some(random_hex(16)?)
+            } else {
+                None
+            };
+            self.connection
+                .write_all(&packed_forward(&tag, &events, chunk.as_deref())?)?;
+
+            if let Some(chunk) = chunk {
+                let answer = self.read_value()?;
+                if answer["ack"].as_slice() != Some(chunk.as_bytes()) {
+                    self.connection.disconnect();
+                    failure::bail!("Expected the ack of chunk {}, got {:?}", chunk, answer);
+                }
+                self.last_ack = format!("ack {}", chunk);
+            }
+        }
+
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn connection(&mut self) -> Option<&mut RemoteConnection> {
+        Some(&mut self.connection)
+    }
+
+    fn acknowledgement(&self) -> String {
+        if self.require_ack {
+            self.last_ack.clone()
+        } else {
+            String::from("not requested, fluent-require-ack is off")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::HostType;
+
+    use std::{io::Write, net::TcpListener, thread};
+
+    fn event(unit: &str, message: &str) -> Event {
+        let mut fields = BTreeMap::new();
+        fields.insert(String::from("_SYSTEMD_UNIT"), unit.to_string());
+        fields.insert(
+            String::from("__REALTIME_TIMESTAMP"),
+            String::from("1792326635000001"),
+        );
+        Event {
+            value: serde_json::json!({ "message": message, "systemd-unit": unit }),
+            cursor: CursorRecord {
+                position: format!("s=1;i={}", message),
+                namespace: String::default(),
+            },
+            fields,
+        }
+    }
+
+    #[test]
+    fn digests_and_tags() {
+        assert_eq!(
+            sha512_hex(&[b"a", b"bc"]),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+        assert_eq!(sha512_hex(&[&[b'x'; 200]]).len(), 128);
+
+        let app = event("app.service", "1");
+        assert_eq!(
+            fluent_tag("journal.{_SYSTEMD_UNIT}", &app),
+            "journal.app.service"
+        );
+        assert_eq!(fluent_tag("{_COMM}.{message}", &app), "unknown.1");
+    }
+
+    #[test]
+    fn batches_are_acknowledged_after_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut helo = vec![];
+            write_array_len(&mut helo, 2).unwrap();
+            write_str(&mut helo, "HELO").unwrap();
+            write_map_len(&mut helo, 3).unwrap();
+            write_str(&mut helo, "nonce").unwrap();
+            write_bin(&mut helo, b"0123456789abcdef").unwrap();
+            write_str(&mut helo, "auth").unwrap();
+            write_bin(&mut helo, b"").unwrap();
+            write_str(&mut helo, "keepalive").unwrap();
+            helo.push(0xc3);
+            stream.write_all(&helo).unwrap();
+
+            let ping = read_value(&mut stream).unwrap();
+            let salt = ping[2].as_slice().unwrap().to_vec();
+            let hostname = ping[1].as_slice().unwrap().to_vec();
+            let expected = sha512_hex(&[&salt, &hostname, b"0123456789abcdef", b"secret"]);
+            assert_eq!(ping[3].as_slice(), Some(expected.as_bytes()));
+            let mut pong = vec![];
+            write_array_len(&mut pong, 5).unwrap();
+            write_str(&mut pong, "PONG").unwrap();
+            pong.push(0xc3);
+            write_str(&mut pong, "").unwrap();
+            write_str(&mut pong, "aggregator").unwrap();
+            write_str(
+                &mut pong,
+                &sha512_hex(&[&salt, b"aggregator", b"0123456789abcdef", b"secret"]),
+            )
+            .unwrap();
+            stream.write_all(&pong).unwrap();
+
+            let mut received = vec![];
+            for _ in 0..2 {
+                let forward = read_value(&mut stream).unwrap();
+                let chunk = forward[2]["chunk"].as_str().unwrap().to_string();
+                let mut ack = vec![0x81];
+                write_str(&mut ack, "ack").unwrap();
+                write_str(&mut ack, &chunk).unwrap();
+                stream.write_all(&ack).unwrap();
+                received.push(forward);
+            }
+            received
+        });
+
+        let mut sink = FluentForwardSink::new(
+            HostRecord {
+                host: String::from("127.0.0.1"),
+                port,
+                kind: HostType::FluentForward,
+                ..HostRecord::default()
+            },
+            "journal.{_SYSTEMD_UNIT}",
+            true,
+            "secret",
+        );
+        let batch = [
+            event("app.service", "1"),
+            event("db.service", "2"),
+            event("app.service", "3"),
+        ];
+        let acknowledged = sink.send(&batch).unwrap();
+        assert_eq!(acknowledged.len(), 3);
+        assert!(sink.acknowledgement().starts_with("ack "));
+
+        let received = server.join().unwrap();
+        let app = &received[0];
+        assert_eq!(app[0].as_str(), Some("journal.app.service"));
+        assert_eq!(app[2]["size"].as_u64(), Some(2));
+        let mut entries = std::io::Cursor::new(app[1].as_slice().unwrap().to_vec());
+        let first = read_value(&mut entries).unwrap();
+        assert_eq!(
+            first[0],
+            MsgValue::Ext(0, vec![0x6a, 0xd4, 0xbb, 0xeb, 0, 0, 0x03, 0xe8])
+        );
+        assert_eq!(first[1]["message"].as_str(), Some("1"));
+        assert_eq!(received[1][0].as_str(), Some("journal.db.service"));
+    }
+}
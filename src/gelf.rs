@@ -0,0 +1,342 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// GELF 1.1 for Graylog. Over TCP every message is plain JSON ended by a null
+// byte, over UDP it is compressed and split into chunks when it does not fit
+// in one datagram.
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    compress::{gzip, zlib},
+    cursor::CursorRecord,
+    pipeline::{Event, Sink},
+    settings::{GelfCompression, HostProtocol},
+    sink::{HostRecord, RemoteConnection},
+    Result,
+};
+
+// The magic bytes, message id, sequence number and count of every chunk
+const CHUNK_HEADER: usize = 12;
+
+// Graylog drops messages of more chunks than this
+const MAX_CHUNKS: usize = 128;
+
+// Keys of the record that have a GELF field of their own
+const MAPPED_KEYS: &[&str] = &["message", "priority", "hostname", "@timestamp"];
+
+static MESSAGE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// The realtime of the record in seconds, with its microseconds
+fn gelf_timestamp(event: &Event) -> Option<f64> {
+    event.timestamp().map(|timestamp| {
+        timestamp.timestamp() as f64 + f64::from(timestamp.timestamp_subsec_micros()) / 1_000_000.0
+    })
+}
+
+// Additional fields are _ and the key, limited to word characters, dots and
+// dashes. _id is reserved by Graylog.
+fn additional_field(key: &str) -> Option<String> {
+    let name: String = key
+        .chars()
+        .map(|character| match character {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => character,
+            _ => '_',
+        })
+        .collect();
+    match name.as_str() {
+        "" | "id" => None,
+        _ => Some(format!("_{}", name)),
+    }
+}
+
+// The GELF message of a record, full_message only when there is more than the
+// first line and it is wanted
+pub fn gelf_message(event: &Event, with_full_message: bool) -> JsonValue {
+    let text = |key: &str| event.value.get(key).and_then(JsonValue::as_str);
+    let message = text("message").unwrap_or_default();
+    let short_message = message
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .unwrap_or("-");
+
+    let mut gelf = JsonMap::new();
+    gelf.insert("version".into(), "1.1".into());
+    gelf.insert("host".into(), text("hostname").unwrap_or("unknown").into());
+    gelf.insert("short_message".into(), short_message.into());
+    if with_full_message && message.trim() != short_message {
+        gelf.insert("full_message".into(), message.into());
+    }
+    if let Some(timestamp) = gelf_timestamp(event) {
+        gelf.insert("timestamp".into(), timestamp.into());
+    }
+    if let Some(level) = text("priority").and_then(|priority| priority.parse::<u8>().ok()) {
+        gelf.insert("level".into(), level.into());
+    }
+
+    if let Some(object) = event.value.as_object() {
+        for (key, value) in object.iter() {
+            if MAPPED_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let name = match additional_field(key) {
+                Some(name) => name,
+                None => continue,
+            };
+            // Only strings and numbers are allowed
+            let value = match value {
+                JsonValue::String(_) | JsonValue::Number(_) => value.clone(),
+                other => other.to_string().into(),
+            };
+            gelf.insert(name, value);
+        }
+    }
+
+    gelf.into()
+}
+
+// An id that differs for every message of this process and between processes
+fn message_id() -> [u8; 8] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    let count = MESSAGE_COUNT.fetch_add(1, Ordering::Relaxed);
+    let id = nanos ^ (u64::from(std::process::id()) << 40) ^ count.rotate_right(16);
+    id.to_be_bytes()
+}
+
+// Split a compressed message into chunks of at most chunk_size bytes, None
+// when that takes more than MAX_CHUNKS
+pub fn gelf_chunks(payload: &[u8], chunk_size: usize) -> Option<Vec<Vec<u8>>> {
+    if payload.len() <= chunk_size {
+        return Some(vec![payload.to_vec()]);
+    }
+
+    let data_size = chunk_size - CHUNK_HEADER;
+    let count = payload.len().div_ceil(data_size);
+    if count > MAX_CHUNKS {
+        return None;
+    }
+
+    let id = message_id();
+    Some(
+        payload
+            .chunks(data_size)
+            .enumerate()
+            .map(|(sequence, data)| {
+                let mut chunk = vec![0x1e, 0x0f];
+                chunk.extend_from_slice(&id);
+                chunk.push(sequence as u8);
+                chunk.push(count as u8);
+                chunk.extend_from_slice(data);
+                chunk
+            })
+            .collect(),
+    )
+}
+
+pub struct GelfSink {
+    connection: RemoteConnection,
+    compression: GelfCompression,
+    chunk_size: usize,
+}
+
+impl GelfSink {
+    pub fn new(host: HostRecord, compression: GelfCompression, chunk_size: usize) -> GelfSink {
+        GelfSink {
+            connection: RemoteConnection::new(host),
+            compression,
+            chunk_size,
+        }
+    }
+
+    fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self.compression {
+            GelfCompression::Gzip => gzip(payload),
+            GelfCompression::Zlib => zlib(payload),
+            GelfCompression::Uncompressed => Ok(payload.to_vec()),
+        }
+    }
+
+    // The writes for a record, one per datagram over UDP. A record that does
+    // not fit in the chunks goes without its full_message, or is dropped.
+    fn writes(&self, event: &Event) -> Result<Vec<Vec<u8>>> {
+        if self.connection.host.protocol != HostProtocol::Udp {
+            let mut bytes = gelf_message(event, true).to_string().into_bytes();
+            bytes.push(0);
+            return Ok(vec![bytes]);
+        }
+
+        for &with_full_message in [true, false].iter() {
+            let payload = self.compress(
+                gelf_message(event, with_full_message)
+                    .to_string()
+                    .as_bytes(),
+            )?;
+            if let Some(chunks) = gelf_chunks(&payload, self.chunk_size) {
+                return Ok(chunks);
+            }
+        }
+
+        eprintln!(
+            " !! The record at {} is too large for {} chunks of {} bytes, it is dropped",
+            event.cursor.position, MAX_CHUNKS, self.chunk_size
+        );
+        Ok(vec![])
+    }
+}
+
+impl Sink for GelfSink {
+    fn label(&self) -> String {
+        self.connection.host.label()
+    }
+
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        for event in batch {
+            bytes.extend(self.writes(event)?.into_iter().flatten());
+        }
+        Ok(bytes)
+    }
+
+    // Part of a batch may be sent twice when the connection fails midway
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        match self.connection.host.protocol {
+            HostProtocol::Tcp | HostProtocol::Tls => {
+                let bytes = self.encode(batch)?;
+                self.connection.write_all(&bytes)?;
+            }
+            HostProtocol::Udp => {
+                for event in batch {
+                    for datagram in self.writes(event)? {
+                        self.connection.write_all(&datagram)?;
+                    }
+                }
+            }
+        }
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn connection(&mut self) -> Option<&mut RemoteConnection> {
+        Some(&mut self.connection)
+    }
+
+    // GELF inputs never answer
+    fn acknowledgement(&self) -> String {
+        String::from("not supported by gelf")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::settings::HostType;
+
+    use std::{collections::BTreeMap, net::UdpSocket};
+
+    fn event(message: &str) -> Event {
+        let mut fields = BTreeMap::new();
+        fields.insert(
+            String::from("__REALTIME_TIMESTAMP"),
+            String::from("1792326635000001"),
+        );
+        Event {
+            value: serde_json::json!({
+                "@timestamp": "2026-10-18T10:30:35.000001Z",
+                "message": message,
+                "priority": "3",
+                "hostname": "web-1",
+                "systemd-unit": "app.service",
+                "id": "reserved",
+                "journald.gap": true,
+            }),
+            cursor: CursorRecord {
+                position: String::from("s=1;i=2"),
+                namespace: String::default(),
+            },
+            fields,
+        }
+    }
+
+    #[test]
+    fn records_map_to_gelf_fields() {
+        let gelf = gelf_message(&event("failed\n  at main.rs:1"), true);
+        assert_eq!(gelf["version"], "1.1");
+        assert_eq!(gelf["host"], "web-1");
+        assert_eq!(gelf["short_message"], "failed");
+        assert_eq!(gelf["full_message"], "failed\n  at main.rs:1");
+        assert_eq!(gelf["timestamp"].to_string(), "1792326635.000001");
+        assert_eq!(gelf["level"], 3);
+        assert_eq!(gelf["_systemd-unit"], "app.service");
+        assert_eq!(gelf["_journald.gap"], "true");
+        assert!(gelf.get("_id").is_none());
+        assert!(gelf.get("_message").is_none());
+
+        let single = gelf_message(&event("one line"), true);
+        assert!(single.get("full_message").is_none());
+    }
+
+    #[test]
+    fn large_messages_are_chunked() {
+        let payload: Vec<u8> = (0..1000u32).map(|index| index as u8).collect();
+        assert_eq!(gelf_chunks(&payload, 1000).unwrap().len(), 1);
+
+        let chunks = gelf_chunks(&payload, 312).unwrap();
+        assert_eq!(chunks.len(), 4);
+        for (sequence, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk[..2], [0x1e, 0x0f]);
+            assert_eq!(chunk[2..10], chunks[0][2..10]);
+            assert_eq!(chunk[10..12], [sequence as u8, 4]);
+            assert!(chunk.len() <= 312);
+        }
+        let joined: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| chunk[CHUNK_HEADER..].to_vec())
+            .collect();
+        assert_eq!(joined, payload);
+        assert_eq!(gelf_chunks(&vec![0; 300 * 129], 312), None);
+    }
+
+    #[test]
+    fn udp_datagrams_are_what_encode_returns() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = GelfSink::new(
+            HostRecord {
+                host: String::from("127.0.0.1"),
+                port: receiver.local_addr().unwrap().port(),
+                protocol: HostProtocol::Udp,
+                kind: HostType::Gelf,
+                ..HostRecord::default()
+            },
+            GelfCompression::Uncompressed,
+            1420,
+        );
+        let batch = [event("hello")];
+        let acknowledged = sink.send(&batch).unwrap();
+        assert_eq!(acknowledged, vec![batch[0].cursor.clone()]);
+
+        let mut datagram = [0u8; 2048];
+        let length = receiver.recv(&mut datagram).unwrap();
+        assert_eq!(datagram[..length].to_vec(), sink.encode(&batch).unwrap());
+        let gelf: JsonValue = serde_json::from_slice(&datagram[..length]).unwrap();
+        assert_eq!(gelf["short_message"], "hello");
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// HTTP for the sinks that post their batches, through ureq. Requests go over
+// TLS for host-protocol tls or an https URL, trusting and presenting the same
+// certificates as the other host types.
+
+use chrono::{DateTime, Utc};
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use std::{fs::OpenOptions, io::Write, time::Duration as StdDuration};
+
+use ureq::Agent;
+
+use crate::{
+    pipeline::Event,
+    settings::HostProtocol,
+    sink::{HostRecord, CONNECT_TIMEOUT, READ_TIMEOUT},
+    tls::ureq_tls_config,
+    Result,
+};
+
+// The longest Retry-After that is waited for
+const MAX_RETRY_AFTER: StdDuration = StdDuration::from_secs(600);
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    // Such as "HTTP/1.1 202 Accepted"
+    pub status_line: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    // Header names are not case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    // Worth sending again, a timeout, too many requests or a failing server
+    pub fn is_transient(&self) -> bool {
+        self.status == 408 || self.status == 429 || self.status >= 500
+    }
+
+    // Refused for good, any other client error would only come back. Missing
+    // or wrong credentials are not, they can be put right while we wait.
+    pub fn is_refusal(&self) -> bool {
+        self.status >= 400
+            && self.status < 500
+            && !self.is_transient()
+            && ![401, 403, 407].contains(&self.status)
+    }
+
+    // Retry-After as seconds or an HTTP date
+    pub fn retry_after(&self) -> Option<StdDuration> {
+        let value = self.header("Retry-After")?;
+        let delay = match value.parse::<u64>() {
+            Ok(seconds) => StdDuration::from_secs(seconds),
+            Err(_) => DateTime::parse_from_rfc2822(value)
+                .ok()?
+                .with_timezone(&Utc)
+                .signed_duration_since(Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+        };
+        Some(delay.min(MAX_RETRY_AFTER))
+    }
+
+    // The start of the body, to go along with an error
+    pub fn body_text(&self) -> String {
+        let text = String::from_utf8_lossy(&self.body);
+        let text = text.trim();
+        match text.char_indices().nth(200) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HttpUrl {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    // With the query, such as /intake?source=journal
+    pub path: String,
+}
+
+// Split an http:// or https:// URL, the port defaults to 80 or 443 and the
+// path to /
+pub fn parse_http_url(url: &str) -> Result<HttpUrl> {
+    let (https, rest) = match url.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (false, rest),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (true, rest),
+        Some((scheme, _)) => {
+            failure::bail!("{} URLs are not supported, only http and https", scheme)
+        }
+        None => failure::bail!("{} is not a URL such as http://host:port/path", url),
+    };
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(split) => (&rest[..split], rest[split..].to_string()),
+        None => (rest, String::from("/")),
+    };
+    let path = if path.starts_with('?') {
+        format!("/{}", path)
+    } else {
+        path
+    };
+
+    // An IPv6 address is in brackets, [::1]:8080
+    let (host, port) = match authority.rfind(':') {
+        Some(split) if !authority[split..].contains(']') => {
+            (&authority[..split], Some(&authority[split + 1..]))
+        }
+        _ => (authority, None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        failure::bail!("{} has no host", url);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| failure::format_err!("{} has no valid port", url))?,
+        None if https => 443,
+        None => 80,
+    };
+
+    Ok(HttpUrl {
+        https,
+        host: host.to_string(),
+        port,
+        path,
+    })
+}
+
+// Escape a query parameter, leaving only the unreserved characters as they are
+pub fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// The client of a sink, which keeps its connection alive between batches.
+// The agent is made on first use, so that a TLS file that cannot be read is
+// a failure to send like any other.
+pub struct HttpClient {
+    pub host: HostRecord,
+    agent: Option<Agent>,
+}
+
+impl HttpClient {
+    pub fn new(host: HostRecord) -> HttpClient {
+        HttpClient { host, agent: None }
+    }
+
+    // Such as https://127.0.0.1:9200/_bulk
+    pub fn url(&self, path: &str) -> String {
+        let scheme = match self.host.protocol {
+            HostProtocol::Tls => "https",
+            _ => "http",
+        };
+        let host = if self.host.host.contains(':') {
+            format!("[{}]", self.host.host)
+        } else {
+            self.host.host.clone()
+        };
+        format!("{}://{}:{}{}", scheme, host, self.host.port, path)
+    }
+
+    fn agent(&mut self) -> Result<Agent> {
+        if let Some(agent) = &self.agent {
+            return Ok(agent.clone());
+        }
+        // Every answer is handed back, redirects included
+        let config = Agent::config_builder()
+            .http_status_as_error(false)
+            .max_redirects(0)
+            .timeout_connect(Some(CONNECT_TIMEOUT))
+            .timeout_recv_response(Some(READ_TIMEOUT))
+            .tls_config(ureq_tls_config(&self.host.tls)?)
+            .build();
+        let agent = Agent::new_with_config(config);
+        self.agent = Some(agent.clone());
+        Ok(agent)
+    }
+
+    pub fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<HttpResponse> {
+        let mut request = ureq::http::Request::builder()
+            .method(method)
+            .uri(self.url(path));
+        for (name, value) in headers.iter() {
+            request = request.header(*name, value.as_str());
+        }
+        let request = request.body(body.to_vec())?;
+
+        let response = self.agent()?.run(request)?;
+        let status = response.status();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        let status_line = format!("{:?} {}", response.version(), status);
+        Ok(HttpResponse {
+            status: status.as_u16(),
+            status_line,
+            headers,
+            body: response.into_body().read_to_vec()?,
+        })
+    }
+}
+
+// Append records that can never be delivered, one JSON document per line
+pub fn append_dead_letters(path: &str, letters: &[JsonValue]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| {
+            failure::format_err!("{:#?}\nwhile trying to open file: {}", error, path)
+        })?;
+    let lines: String = letters
+        .iter()
+        .map(|letter| format!("{}\n", letter))
+        .collect();
+    file.write_all(lines.as_bytes())?;
+    Ok(())
+}
+
+// Set a batch aside that the destination refused for good, rather than send
+// it again forever. Each record goes to the dead letter file along with the
+// answer, and the refusal is reported with the number of records once they
+// are all written.
+pub fn refuse_batch(
+    dead_letter: &str,
+    label: &str,
+    batch: &[Event],
+    response: &HttpResponse,
+) -> Result<()> {
+    let timestamp = Utc::now().to_rfc3339().replace("+00:00", "Z");
+    let letters: Vec<JsonValue> = batch
+        .iter()
+        .map(|event| {
+            let mut letter = JsonMap::new();
+            letter.insert("@timestamp".into(), timestamp.clone().into());
+            letter.insert("destination".into(), label.into());
+            letter.insert("status".into(), response.status.into());
+            letter.insert("error".into(), response.body_text().into());
+            letter.insert("document".into(), event.value.clone());
+            letter.into()
+        })
+        .collect();
+    append_dead_letters(dead_letter, &letters)?;
+
+    eprintln!(
+        " !! {} records were refused with {}, they are written to {}: {}\nwhile sending to: {}",
+        batch.len(),
+        response.status_line,
+        dead_letter,
+        response.body_text(),
+        label
+    );
+    Ok(())
+}
+
+// Read one request on the server side of a test, None once the client is gone
+#[cfg(test)]
+pub fn read_request<R: std::io::BufRead>(reader: &mut R) -> Option<(Vec<String>, Vec<u8>)> {
+    let mut head = vec![];
+    loop {
+        let mut line = String::default();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        if line == "\r\n" {
+            break;
+        }
+        head.push(line.trim_end().to_string());
+    }
+    let length: usize = head
+        .iter()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.eq_ignore_ascii_case("Content-Length") {
+                value.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default();
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).ok()?;
+    Some((head, body))
+}
+
+// Answer each request of a test in turn with the raw responses given, over as
+// many connections as the client makes, returning the heads and bodies
+#[cfg(test)]
+pub fn serve_requests(
+    listener: std::net::TcpListener,
+    answers: Vec<String>,
+) -> std::thread::JoinHandle<Vec<(Vec<String>, Vec<u8>)>> {
+    std::thread::spawn(move || {
+        let mut requests = vec![];
+        let mut answers = answers.into_iter();
+        'connections: while let Ok((stream, _)) = listener.accept() {
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            while let Some(request) = read_request(&mut reader) {
+                requests.push(request);
+                let answer = answers.next().unwrap();
+                (&stream).write_all(answer.as_bytes()).unwrap();
+                if answers.len() == 0 {
+                    break 'connections;
+                }
+            }
+        }
+        requests
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_sorted_into_success_retry_and_refusal() {
+        let answer = |status: u16, headers: Vec<(String, String)>| HttpResponse {
+            status,
+            headers,
+            ..HttpResponse::default()
+        };
+        assert!(answer(202, vec![]).is_success());
+        for status in [408, 429, 500, 503].iter() {
+            assert!(answer(*status, vec![]).is_transient(), "{}", status);
+            assert!(!answer(*status, vec![]).is_refusal(), "{}", status);
+        }
+        for status in [400, 404, 413].iter() {
+            assert!(answer(*status, vec![]).is_refusal(), "{}", status);
+        }
+        for status in [401, 403, 407].iter() {
+            assert!(!answer(*status, vec![]).is_refusal(), "{}", status);
+        }
+        assert!(!answer(302, vec![]).is_refusal());
+
+        let limited = answer(429, vec![(String::from("retry-after"), String::from("7"))]);
+        assert_eq!(limited.header("Retry-After"), Some("7"));
+        assert_eq!(limited.retry_after(), Some(StdDuration::from_secs(7)));
+    }
+
+    #[test]
+    fn requests_go_over_https_with_the_tls_files() {
+        use rustls::{ServerConnection, StreamOwned};
+        use std::{io::BufReader, sync::Arc};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = crate::tls::test_server_config();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut reader = BufReader::new(StreamOwned::new(connection, stream));
+            let request = read_request(&mut reader).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 202 Accepted\r\nContent-Length: 3\r\n\r\nOK.")
+                .unwrap();
+            reader.get_mut().flush().unwrap();
+            request
+        });
+
+        let fixture = crate::tls::test_fixture;
+        let mut client = HttpClient::new(HostRecord {
+            host: String::from("localhost"),
+            port,
+            protocol: HostProtocol::Tls,
+            tls: crate::tls::TlsFiles {
+                ca_file: fixture("ca.pem"),
+                cert_file: fixture("client.pem"),
+                key_file: fixture("client.key"),
+            },
+            ..HostRecord::default()
+        });
+        assert_eq!(
+            client.url("/upload"),
+            format!("https://localhost:{}/upload", port)
+        );
+        let response = client.request("POST", "/upload", &[], b"hello").unwrap();
+        assert_eq!(response.status_line, "HTTP/1.1 202 Accepted");
+        assert_eq!(response.body_text(), "OK.");
+
+        let (head, body) = server.join().unwrap();
+        assert_eq!(head[0], "POST /upload HTTP/1.1");
+        assert_eq!(body, b"hello".to_vec());
+    }
+
+    #[test]
+    fn parameters_are_encoded() {
+        assert_eq!(url_encode("journal pipeline/v1"), "journal%20pipeline%2Fv1");
+    }
+
+    #[test]
+    fn urls_are_split() {
+        assert_eq!(
+            parse_http_url("http://[::1]:8080/intake?source=journal").unwrap(),
+            HttpUrl {
+                https: false,
+                host: String::from("::1"),
+                port: 8080,
+                path: String::from("/intake?source=journal"),
+            }
+        );
+        let plain = parse_http_url("HTTP://collector").unwrap();
+        assert_eq!(
+            (plain.https, plain.port, plain.path.as_str()),
+            (false, 80, "/")
+        );
+        let secure = parse_http_url("https://collector/intake").unwrap();
+        assert_eq!((secure.https, secure.port), (true, 443));
+        assert!(parse_http_url("ftp://collector/").is_err());
+        assert!(parse_http_url("collector:80").is_err());
+    }
+}
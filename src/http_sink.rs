@@ -0,0 +1,346 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// Posts batches to any HTTP endpoint that takes JSON, as NDJSON or as one
+// JSON array. A batch is acknowledged on a 2xx answer. With http-dead-letter
+// set, so is one refused with a 4xx other than 401, 403, 407, 408 and 429,
+// once it is in that file. Every other answer has it sent again, after
+// Retry-After when the endpoint gives one.
+
+use std::time::Duration as StdDuration;
+
+use crate::{
+    compress::gzip,
+    cursor::CursorRecord,
+    http::{parse_http_url, refuse_batch, HttpClient},
+    pipeline::{Event, Sink, RETRY_DELAY},
+    settings::{HostProtocol, HttpBodyFormat, Settings},
+    sink::{wire_bytes, HostRecord},
+    Result,
+};
+
+// A header of http-headers, such as "X-Source: journal"
+pub fn parse_header(header: &str) -> Option<(String, String)> {
+    let (name, value) = header.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name.to_string(), value.trim().to_string()))
+}
+
+pub struct HttpSink {
+    client: HttpClient,
+    path: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    bearer_token_file: String,
+    body_format: HttpBodyFormat,
+    gzip: bool,
+    dead_letter: String,
+    batch_size: usize,
+    linger: StdDuration,
+    retry_after: Option<StdDuration>,
+    status_line: String,
+}
+
+impl HttpSink {
+    // The scheme, host and path come from http-url when it is set, otherwise
+    // the records go to / of host-name and host-port
+    pub fn new(mut host: HostRecord, settings: &Settings) -> HttpSink {
+        let mut path = String::from("/");
+        if let Ok(url) = parse_http_url(&settings.http_url) {
+            host.protocol = if url.https {
+                HostProtocol::Tls
+            } else {
+                HostProtocol::Tcp
+            };
+            host.host = url.host;
+            host.port = url.port;
+            path = url.path;
+        }
+
+        HttpSink {
+            client: HttpClient::new(host),
+            path,
+            method: settings.http_method.clone(),
+            headers: settings
+                .http_headers
+                .iter()
+                .filter_map(|header| parse_header(header))
+                .collect(),
+            bearer_token_file: settings.http_bearer_token_file.clone(),
+            body_format: settings.http_body_format,
+            gzip: settings.http_gzip,
+            dead_letter: settings.http_dead_letter.clone(),
+            batch_size: settings.http_batch_size,
+            linger: settings.http_linger,
+            retry_after: None,
+            status_line: String::default(),
+        }
+    }
+
+    // Read for every request, so that a rotated token is picked up
+    fn authorization(&self) -> Result<Option<String>> {
+        if self.bearer_token_file.is_empty() {
+            return Ok(None);
+        }
+        let token = std::fs::read_to_string(&self.bearer_token_file).map_err(|error| {
+            failure::format_err!(
+                "{:#?}\nwhile trying to open file: {}",
+                error,
+                self.bearer_token_file
+            )
+        })?;
+        Ok(Some(format!("Bearer {}", token.trim())))
+    }
+}
+
+impl Sink for HttpSink {
+    fn label(&self) -> String {
+        format!("http {}", self.client.url(&self.path))
+    }
+
+    // Compressed with http-gzip
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let body = match self.body_format {
+            HttpBodyFormat::Ndjson => batch
+                .iter()
+                .flat_map(|event| wire_bytes(&event.value))
+                .collect(),
+            HttpBodyFormat::JsonArray => {
+                let values: Vec<&serde_json::Value> =
+                    batch.iter().map(|event| &event.value).collect();
+                serde_json::to_vec(&values)?
+            }
+        };
+        if self.gzip {
+            gzip(&body)
+        } else {
+            Ok(body)
+        }
+    }
+
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        self.retry_after = None;
+
+        let body = self.encode(batch)?;
+        let mut headers: Vec<(&str, String)> = vec![(
+            "Content-Type",
+            String::from(match self.body_format {
+                HttpBodyFormat::Ndjson => "application/x-ndjson",
+                HttpBodyFormat::JsonArray => "application/json",
+            }),
+        )];
+        if self.gzip {
+            headers.push(("Content-Encoding", String::from("gzip")));
+        }
+        let authorization = self.authorization()?;
+        if let Some(authorization) = authorization {
+            headers.push(("Authorization", authorization));
+        }
+        for (name, value) in self.headers.iter() {
+            headers.push((name.as_str(), value.clone()));
+        }
+
+        let response = self
+            .client
+            .request(&self.method, &self.path, &headers, &body)?;
+        self.status_line = response.status_line.clone();
+
+        // Refused for good, the batch is only acknowledged once http-dead-letter
+        // holds it, without one it is tried again like any other failure
+        if !self.dead_letter.is_empty() && response.is_refusal() {
+            refuse_batch(&self.dead_letter, &self.label(), batch, &response)?;
+        } else if !response.is_success() {
+            if response.is_transient() {
+                self.retry_after = response.retry_after();
+            }
+            failure::bail!(
+                "The request was refused with {}: {}",
+                response.status_line,
+                response.body_text()
+            );
+        }
+
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn http_client(&self) -> Option<&HttpClient> {
+        Some(&self.client)
+    }
+
+    fn acknowledgement(&self) -> String {
+        self.status_line.clone()
+    }
+
+    fn max_batch(&self) -> usize {
+        self.batch_size
+    }
+
+    fn linger(&self) -> StdDuration {
+        self.linger
+    }
+
+    fn retry_delay(&self) -> StdDuration {
+        self.retry_after.unwrap_or(RETRY_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::http::serve_requests;
+
+    use std::{io::Read, net::TcpListener};
+
+    #[test]
+    fn busy_endpoints_are_retried_after_the_delay_they_ask_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_requests(
+            listener,
+            vec![
+                String::from(
+                    "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3\r\nContent-Length: 0\r\n\r\n",
+                ),
+                String::from("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"),
+            ],
+        );
+
+        let token_file =
+            std::env::temp_dir().join(format!("journaldeliver-token-{}", std::process::id()));
+        std::fs::write(&token_file, "s3cret\n").unwrap();
+        let settings = Settings {
+            http_url: format!("http://127.0.0.1:{}/intake?source=journal", port),
+            http_method: String::from("PUT"),
+            http_headers: vec![String::from("X-Source: journal")],
+            http_bearer_token_file: token_file.to_string_lossy().to_string(),
+            http_body_format: HttpBodyFormat::JsonArray,
+            http_gzip: true,
+            ..Settings::default()
+        };
+        let mut sink = HttpSink::new(HostRecord::default(), &settings);
+        assert_eq!(
+            sink.label(),
+            format!("http http://127.0.0.1:{}/intake?source=journal", port)
+        );
+        let batch = [Event {
+            value: serde_json::json!({ "message": "hello" }),
+            cursor: CursorRecord {
+                position: String::from("s=1;i=2"),
+                namespace: String::default(),
+            },
+            fields: std::collections::BTreeMap::new(),
+        }];
+        let encoded = sink.encode(&batch).unwrap();
+        let mut decoded = String::default();
+        flate2::read::GzDecoder::new(&encoded[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "[{\"message\":\"hello\"}]");
+
+        assert!(sink.send(&batch).is_err());
+        assert_eq!(sink.retry_delay(), StdDuration::from_secs(3));
+        assert_eq!(sink.send(&batch).unwrap(), vec![batch[0].cursor.clone()]);
+        assert_eq!(sink.retry_delay(), RETRY_DELAY);
+        std::fs::remove_file(&token_file).unwrap();
+
+        let requests = server.join().unwrap();
+        let (head, body) = &requests[1];
+        assert_eq!(head[0], "PUT /intake?source=journal HTTP/1.1");
+        for header in [
+            "content-type: application/json",
+            "content-encoding: gzip",
+            "authorization: Bearer s3cret",
+            "x-source: journal",
+        ]
+        .iter()
+        {
+            assert!(head.contains(&header.to_string()), "{}", header);
+        }
+        assert_eq!(body, &encoded);
+    }
+
+    #[test]
+    fn refused_requests_are_dead_lettered_or_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_requests(
+            listener,
+            vec![
+                String::from("HTTP/1.1 403 Forbidden\r\nContent-Length: 6\r\n\r\ndenied"),
+                String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 7\r\n\r\nmissing"),
+                String::from(
+                    "HTTP/1.1 307 Temporary Redirect\r\nLocation: /\r\nContent-Length: 0\r\n\r\n",
+                ),
+                String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"),
+            ],
+        );
+
+        let dead_letter = std::env::temp_dir().join(format!(
+            "journaldeliver-http-dead-letter-{}.ndjson",
+            std::process::id()
+        ));
+        let settings = Settings {
+            http_url: format!("http://127.0.0.1:{}/intake", port),
+            http_dead_letter: dead_letter.to_string_lossy().to_string(),
+            ..Settings::default()
+        };
+        let batch = [Event {
+            value: serde_json::json!({ "message": "hello" }),
+            cursor: CursorRecord {
+                position: String::from("s=1;i=2"),
+                namespace: String::default(),
+            },
+            fields: std::collections::BTreeMap::new(),
+        }];
+
+        let mut sink = HttpSink::new(HostRecord::default(), &settings);
+        let error = sink.send(&batch).unwrap_err().to_string();
+        assert!(error.contains("403 Forbidden"), "{}", error);
+        assert_eq!(sink.send(&batch).unwrap(), vec![batch[0].cursor.clone()]);
+        assert!(sink.send(&batch).is_err());
+        assert_eq!(sink.retry_delay(), RETRY_DELAY);
+        // The server answers one connection at a time
+        drop(sink);
+        let mut retrying = HttpSink::new(
+            HostRecord::default(),
+            &Settings {
+                http_dead_letter: String::default(),
+                ..settings.clone()
+            },
+        );
+        assert!(retrying.send(&batch).is_err());
+        assert_eq!(server.join().unwrap().len(), 4);
+
+        let letters = std::fs::read_to_string(&dead_letter).unwrap();
+        std::fs::remove_file(&dead_letter).unwrap();
+        assert_eq!(letters.lines().count(), 1);
+        let letter: serde_json::Value = serde_json::from_str(letters.trim_end()).unwrap();
+        assert_eq!(letter["status"], 404);
+        assert_eq!(letter["error"], "missing");
+        assert_eq!(letter["document"]["message"], "hello");
+
+        let secure = HttpSink::new(
+            HostRecord::default(),
+            &Settings {
+                http_url: String::from("https://collector.example/intake"),
+                ..Settings::default()
+            },
+        );
+        assert_eq!(secure.label(), "http https://collector.example:443/intake");
+    }
+}
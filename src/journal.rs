@@ -0,0 +1,910 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use nix::libc::{c_int, getrusage, rusage, timeval, RUSAGE_SELF};
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use std::{
+    collections::BTreeMap, result::Result as StdResult, sync::mpsc, time::Duration as StdDuration,
+};
+
+use systemd::journal::{
+    Journal, JournalSeek, OpenDirectoryOptions, OpenFilesOptions, OpenOptions as JournalOpenOptions,
+};
+
+use crate::{
+    cursor::{cursor_field, CursorRecord, HEAD_POSITION},
+    pipeline::{forward, Event, Source, Transform},
+    settings::{parse_point_in_time, CursorRecovery, HistoryType, JournalSource, Settings},
+    Result,
+};
+
+// The default namespace is represented by the empty string
+pub fn get_namespaces(settings: &Settings) -> Result<Vec<String>> {
+    let mut namespaces: Vec<String> = vec![];
+
+    // Namespaces only apply to the system journal, not to a directory, a file
+    // set or an export
+    let has_path = !settings.journal_directory.is_empty()
+        || !settings.journal_files.is_empty()
+        || !settings.journal_export.is_empty();
+
+    if settings.namespaces.is_empty() || has_path {
+        return Ok(vec![String::default()]);
+    }
+
+    for namespace in settings.namespaces.iter().cloned() {
+        if namespace == "*" {
+            // Same as journalctl, all namespaces include the default one
+            namespaces.push(String::default());
+            let machine_id = std::fs::read_to_string("/etc/machine-id")?;
+            let prefix = format!("{}.", machine_id.trim());
+            for journal_root in &["/run/log/journal", "/var/log/journal"] {
+                if let Ok(entries) = std::fs::read_dir(journal_root) {
+                    for entry in entries.filter_map(StdResult::ok) {
+                        let name = entry.file_name().to_string_lossy().to_string();
+                        if let Some(found) = name.strip_prefix(&prefix) {
+                            namespaces.push(found.to_string());
+                        }
+                    }
+                }
+            }
+        } else if let Some(name) = namespace.strip_prefix('+') {
+            // Same as journalctl, a leading + also includes the default namespace
+            namespaces.push(String::default());
+            namespaces.push(name.to_string());
+        } else {
+            namespaces.push(namespace);
+        }
+    }
+
+    namespaces.sort();
+    namespaces.dedup();
+
+    Ok(namespaces)
+}
+
+pub fn open_journal(settings: &Settings, namespace: &str) -> Result<Journal> {
+    let (system, current_user) = match settings.journal_source {
+        JournalSource::All => (false, false),
+        JournalSource::System => (true, false),
+        JournalSource::User => (false, true),
+    };
+
+    let journal_files = &settings.journal_files;
+    let journal_directory = &settings.journal_directory;
+
+    // Explicit files win over a directory, which wins over the system default
+    let journal = if !journal_files.is_empty() {
+        OpenFilesOptions::default().open_files(journal_files.iter().map(String::as_str))?
+    } else if !journal_directory.is_empty() {
+        OpenDirectoryOptions::default()
+            .system(system)
+            .current_user(current_user)
+            .open_directory(journal_directory.as_str())?
+    } else {
+        let mut options = JournalOpenOptions::default();
+        options
+            .system(system)
+            .current_user(current_user)
+            .runtime_only(settings.journal_runtime_only)
+            .local_only(settings.journal_local_only);
+        if namespace.is_empty() {
+            options.open()?
+        } else {
+            options.open_namespace(namespace)?
+        }
+    };
+
+    Ok(journal)
+}
+
+// Seek and then settle on a real record, returning its cursor or an empty
+// string when the journal is empty. A seek past the last record settles on
+// the last record, so nothing already in the journal is read again.
+pub fn seek_journal(journal: &mut Journal, seek: JournalSeek) -> Result<String> {
+    let tail = seek == JournalSeek::Tail;
+
+    journal.seek(seek)?;
+    if journal.cursor().is_err() && (tail || journal.next()? == 0) {
+        journal.seek(JournalSeek::Tail)?;
+        journal.previous()?;
+    }
+
+    Ok(journal.cursor().unwrap_or_default())
+}
+
+// Position on the last record before a realtime so that following starts at
+// the first record at or after it. An empty cursor means start at the head.
+pub fn seek_before_time(journal: &mut Journal, usec: u64) -> Result<String> {
+    // Going backwards from a realtime seek includes that exact time
+    journal.seek(JournalSeek::ClockRealtime {
+        usec: usec.saturating_sub(1),
+    })?;
+    match journal.previous()? {
+        0 => Ok(String::default()),
+        _ => Ok(journal.cursor()?),
+    }
+}
+
+// Position on the record after which following should start. A positive
+// count passes over the first records, a negative count leaves the last
+// records to be read and zero leaves only new records. When the journal is
+// shorter than the count this is the tail or the head respectively, where an
+// empty cursor means start at the head.
+pub fn seek_count(journal: &mut Journal, count: i64) -> Result<String> {
+    let skip = count.unsigned_abs();
+
+    if count > 0 {
+        journal.seek(JournalSeek::Head)?;
+        if journal.next_skip(skip)? == 0 {
+            return Ok(String::default());
+        }
+    } else {
+        // One more than the records to read, that is where we stop
+        journal.seek(JournalSeek::Tail)?;
+        if journal.previous_skip(skip + 1)? as u64 <= skip {
+            return Ok(String::default());
+        }
+    }
+
+    Ok(journal.cursor()?)
+}
+
+// Returns the cursor and realtime of the first record of a boot
+fn first_record_of_boot(journal: &mut Journal, boot_id: &str) -> Result<Option<(String, u64)>> {
+    journal.match_add("_BOOT_ID", boot_id)?;
+    journal.seek(JournalSeek::Head)?;
+    let first = match journal.next_entry()? {
+        Some(_) => Some((journal.cursor()?, journal.timestamp_usec()?)),
+        None => None,
+    };
+    journal.match_flush()?;
+
+    Ok(first)
+}
+
+// Walk back from the latest boot, 0 being the latest and -1 the one before it
+fn find_boot_id(journal: &mut Journal, offset: i64) -> Result<String> {
+    journal.seek(JournalSeek::Tail)?;
+    let mut boot_id = match journal.previous_entry()? {
+        Some(record) => record.get("_BOOT_ID").cloned().unwrap_or_default(),
+        None => failure::bail!("The journal is empty, there is no boot to seek to"),
+    };
+
+    for _ in offset..0 {
+        let (first_cursor, _) = first_record_of_boot(journal, &boot_id)?
+            .ok_or_else(|| failure::format_err!("Boot {} has no records", boot_id))?;
+        journal.seek(JournalSeek::Cursor {
+            cursor: first_cursor,
+        })?;
+        journal.next()?;
+        boot_id = match journal.previous_entry()? {
+            Some(record) => record.get("_BOOT_ID").cloned().unwrap_or_default(),
+            None => failure::bail!("There is no boot {} in the journal", offset),
+        };
+    }
+
+    Ok(boot_id)
+}
+
+// Position on the last record before the boot so that following starts with
+// the first record of the boot. An empty cursor means start at the head.
+fn seek_boot(journal: &mut Journal, boot_id: &str) -> Result<String> {
+    let (first_cursor, _) = first_record_of_boot(journal, boot_id)?
+        .ok_or_else(|| failure::format_err!("There is no boot {} in the journal", boot_id))?;
+
+    journal.seek(JournalSeek::Cursor {
+        cursor: first_cursor,
+    })?;
+    journal.next()?;
+    match journal.previous()? {
+        0 => Ok(String::default()),
+        _ => Ok(journal.cursor()?),
+    }
+}
+
+pub fn seek_history(settings: &Settings, journal: &mut Journal) -> Result<String> {
+    let verbose = settings.verbose;
+
+    match settings.history_type {
+        HistoryType::Duration => {
+            let duration = Duration::from_std(settings.history_duration)?;
+
+            if verbose > 1 {
+                eprintln!(" .. Seek Duration: {:?}", duration);
+            }
+
+            if duration != Duration::seconds(0) {
+                let now: DateTime<Utc> = Utc::now();
+
+                let start_time: u64 = now
+                    .checked_sub_signed(duration)
+                    .ok_or_else(|| {
+                        failure::format_err!("history-duration {} reaches back too far", duration)
+                    })?
+                    .timestamp()
+                    .to_string()
+                    .parse::<u64>()?
+                    * 1_000_000;
+
+                seek_before_time(journal, start_time)
+            } else {
+                seek_journal(journal, JournalSeek::Tail)
+            }
+        }
+        HistoryType::Absolute => {
+            let absolute = settings
+                .history_absolute
+                .timestamp()
+                .to_string()
+                .parse::<u64>()?
+                * 1_000_000;
+
+            if verbose > 1 {
+                eprintln!(" .. Seek Absolute: {:?}", absolute);
+            }
+
+            seek_before_time(journal, absolute)
+        }
+        HistoryType::Count => {
+            let count: i64 = settings.history_count;
+
+            if verbose > 1 {
+                eprintln!(" .. Seek Records: {:?}", count);
+            }
+
+            seek_count(journal, count)
+        }
+        HistoryType::Boot => {
+            let boot = &settings.history_boot;
+
+            if verbose > 1 {
+                eprintln!(" .. Seek Boot: {}", boot);
+            }
+
+            let boot_id = match boot.parse::<i64>() {
+                Ok(offset) if offset <= 0 => find_boot_id(journal, offset)?,
+                Ok(offset) => failure::bail!("{} is not a valid history-boot!", offset),
+                Err(_) => boot.replace("-", "").to_lowercase(),
+            };
+
+            seek_boot(journal, &boot_id)
+        }
+    }
+}
+
+// The saved cursor is no longer in the journal, most likely because its file
+// was rotated or vacuumed away. Reposition according to cursor-recovery and
+// return the policy that was applied.
+pub fn recover_cursor(
+    settings: &Settings,
+    journal: &mut Journal,
+    position: &str,
+) -> Result<String> {
+    let realtime = cursor_field(position, "t").and_then(|t| u64::from_str_radix(&t, 16).ok());
+
+    match (settings.cursor_recovery, realtime) {
+        (CursorRecovery::Head, _) | (CursorRecovery::Timestamp, None) => {
+            journal.seek(JournalSeek::Head)?;
+            Ok(String::from("head"))
+        }
+        (CursorRecovery::Tail, _) => {
+            seek_journal(journal, JournalSeek::Tail)?;
+            Ok(String::from("tail"))
+        }
+        (CursorRecovery::Timestamp, Some(usec)) => {
+            // The record at t= was already sent, continue with the one after it
+            journal.seek(JournalSeek::ClockRealtime { usec: usec + 1 })?;
+            Ok(String::from("timestamp"))
+        }
+    }
+}
+
+// A synthetic record telling downstream that records may have been lost
+pub fn gap_event(lost_cursor: &CursorRecord, policy: &str) -> JsonValue {
+    let timestamp_str = Utc::now().to_rfc3339().replace("+00:00", "Z");
+    let mut json_map = JsonMap::new();
+    json_map.insert("@timestamp".into(), timestamp_str.into());
+    json_map.insert("journald.gap".into(), true.into());
+    json_map.insert(
+        "journald.cursor".into(),
+        lost_cursor.position.clone().into(),
+    );
+    json_map.insert(
+        "journald.namespace".into(),
+        lost_cursor.namespace.clone().into(),
+    );
+    json_map.insert("journald.recovery".into(), policy.into());
+    json_map.insert(
+        "message".into(),
+        format!(
+            "The last cursor is no longer in the journal, records may have been lost. \
+             Continuing from the {}.",
+            policy
+        )
+        .into(),
+    );
+    json_map.into()
+}
+
+// Reads one namespace of the journal as a Source. Without an until it follows
+// the journal, waiting for new records, until main-loop-count reads are done
+// so that the child can be recycled.
+pub struct JournalReader {
+    journal: Journal,
+    namespace: String,
+    until: Option<u64>,
+    verbose: i64,
+    main_loop_count: i64,
+    main_loop_message: i64,
+    loop_count: i64,
+    sleep_count: i64,
+    old_mem_value: i64,
+    position: String,
+    // The gap record, handed out before anything is read
+    pending: Option<Event>,
+}
+
+impl JournalReader {
+    pub fn open(
+        settings: &Settings,
+        namespace: &str,
+        position: &str,
+        until: Option<u64>,
+    ) -> Result<JournalReader> {
+        let verbose = settings.verbose;
+        let mut journal = open_journal(settings, namespace)?;
+        let mut pending = None;
+
+        seek_journal(
+            &mut journal,
+            JournalSeek::Cursor {
+                cursor: position.to_string(),
+            },
+        )
+        .unwrap_or_default();
+        if position == HEAD_POSITION {
+            journal.seek(JournalSeek::Head)?;
+        } else if !position.is_empty() && !journal.test_cursor(position).unwrap_or(false) {
+            let policy = recover_cursor(settings, &mut journal, position)?;
+            if verbose >= 1 {
+                eprintln!(
+                    " !! Cursor not found, recovered from the {}: {} [{}]",
+                    policy, position, namespace
+                );
+            }
+            let lost_cursor = CursorRecord {
+                position: position.to_string(),
+                namespace: namespace.to_string(),
+            };
+            // The gap has no position of its own, so the saved cursor is left alone
+            pending = Some(Event {
+                value: gap_event(&lost_cursor, &policy),
+                cursor: CursorRecord {
+                    position: String::default(),
+                    namespace: namespace.to_string(),
+                },
+                fields: BTreeMap::new(),
+            });
+        }
+
+        Ok(JournalReader {
+            journal,
+            namespace: namespace.to_string(),
+            until,
+            verbose,
+            // A bounded replay is never interrupted to recycle the child
+            main_loop_count: match until {
+                Some(_) => i64::MAX,
+                None => settings.main_loop_count,
+            },
+            main_loop_message: settings.main_loop_message,
+            loop_count: 0,
+            sleep_count: 0,
+            old_mem_value: 0,
+            position: position.to_string(),
+            pending,
+        })
+    }
+
+    // need to do this because journald does not cleanup after itself
+    fn report_usage(&mut self) {
+        if self.loop_count % self.main_loop_message == 0 {
+            eprintln!(" <> Loop/Sleep {}/{}", self.loop_count, self.sleep_count);
+            eprintln!(" ++ Cursor: {}", self.position);
+        }
+        let mut stats = rusage {
+            ru_utime: timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            ru_stime: timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            ru_maxrss: 0,
+            ru_ixrss: 0,
+            ru_idrss: 0,
+            ru_isrss: 0,
+            ru_minflt: 0,
+            ru_majflt: 0,
+            ru_nswap: 0,
+            ru_inblock: 0,
+            ru_oublock: 0,
+            ru_msgsnd: 0,
+            ru_msgrcv: 0,
+            ru_nsignals: 0,
+            ru_nvcsw: 0,
+            ru_nivcsw: 0,
+        };
+        let stats_ptr: *mut rusage = &mut stats;
+        let usage_result: c_int;
+        unsafe {
+            usage_result = getrusage(RUSAGE_SELF, stats_ptr);
+        }
+        if usage_result == 0 && self.old_mem_value != stats.ru_maxrss {
+            eprintln!(" -- Max RSS {}", stats.ru_maxrss);
+            self.old_mem_value = stats.ru_maxrss;
+        }
+    }
+}
+
+impl Source for JournalReader {
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        if let Some(event) = self.pending.take() {
+            return Ok(Some(event));
+        }
+
+        loop {
+            self.loop_count += 1;
+            if self.loop_count >= self.main_loop_count {
+                return Ok(None);
+            }
+            if self.verbose >= 3 && self.loop_count % 1_000 == 0 {
+                self.report_usage();
+            }
+
+            let record = match self.journal.next_entry()? {
+                Some(matched_record) => matched_record,
+                None if self.until.is_some() => return Ok(None),
+                None => loop {
+                    if let Some(matched_record) = self.journal.await_next_entry(None)? {
+                        self.sleep_count += 1;
+                        break matched_record;
+                    }
+                },
+            };
+
+            if let Some(until_usec) = self.until {
+                if self.journal.timestamp_usec()? > until_usec {
+                    return Ok(None);
+                }
+            }
+
+            let position = self.journal.cursor().unwrap_or_default();
+            if position.is_empty() {
+                continue;
+            }
+            self.position = position.clone();
+
+            let timestamp: DateTime<Utc> = self
+                .journal
+                .timestamp()
+                .unwrap_or_else(|_| Utc::now().into())
+                .into();
+            let mut record = record;
+            record.insert(String::from("__CURSOR"), position.clone());
+            if let Ok(realtime) = self.journal.timestamp_usec() {
+                record.insert(String::from("__REALTIME_TIMESTAMP"), realtime.to_string());
+            }
+            if let Ok((monotonic, _)) = self.journal.monotonic_timestamp() {
+                record.insert(String::from("__MONOTONIC_TIMESTAMP"), monotonic.to_string());
+            }
+
+            return Ok(Some(record_event(
+                record,
+                timestamp,
+                CursorRecord {
+                    position,
+                    namespace: self.namespace.clone(),
+                },
+            )));
+        }
+    }
+}
+
+// A journal record as JSON, _SYSTEMD_UNIT for example becomes systemd-unit.
+// The address fields such as __CURSOR are kept out of the JSON.
+pub fn record_event(
+    record: BTreeMap<String, String>,
+    timestamp: DateTime<Utc>,
+    cursor: CursorRecord,
+) -> Event {
+    let timestamp_str = timestamp.to_rfc3339().replace("+00:00", "Z");
+    let mut json_map = JsonMap::new();
+    json_map.insert("@timestamp".into(), timestamp_str.clone().into());
+    json_map.insert("journald.timestamp".into(), timestamp_str.into());
+    json_map.insert("journald.cursor".into(), cursor.position.clone().into());
+    json_map.insert("journald.namespace".into(), cursor.namespace.clone().into());
+    record
+        .iter()
+        .filter(|(record_key, _)| !record_key.starts_with("__"))
+        .for_each(|(record_key, record_value)| {
+            json_map.insert(
+                record_key
+                    .to_lowercase()
+                    .replace("_", "-")
+                    .trim_start_matches('-')
+                    .replace("source", "originator"),
+                record_value.as_str().into(),
+            );
+        });
+
+    Event {
+        value: json_map.into(),
+        cursor,
+        fields: record,
+    }
+}
+
+// Returns the number of records handed to the sender
+pub fn follow_journal(
+    settings: &Settings,
+    namespace: &str,
+    position: &str,
+    until: Option<u64>,
+    transforms: &mut [Box<dyn Transform>],
+    sender: &mpsc::SyncSender<Event>,
+) -> Result<u64> {
+    let mut reader = JournalReader::open(settings, namespace, position, until)?;
+
+    forward(&mut reader, transforms, sender)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct CursorStatus {
+    pub namespace: String,
+    pub position: String,
+    pub fields: BTreeMap<String, String>,
+    pub found: bool,
+    pub timestamp: Option<String>,
+    pub tail_timestamp: Option<String>,
+    pub lag: Option<String>,
+}
+
+pub fn usec_to_rfc3339(usec: u64) -> String {
+    Utc.timestamp((usec / 1_000_000) as i64, (usec % 1_000_000) as u32 * 1_000)
+        .to_rfc3339()
+        .replace("+00:00", "Z")
+}
+
+pub fn cursor_status(settings: &Settings, record: &CursorRecord) -> Result<CursorStatus> {
+    let names = [
+        ("s", "seqnum-id"),
+        ("i", "seqnum"),
+        ("b", "boot-id"),
+        ("m", "monotonic"),
+        ("t", "realtime"),
+        ("x", "xor-hash"),
+    ];
+    let mut status = CursorStatus {
+        namespace: record.namespace.clone(),
+        position: record.position.clone(),
+        ..CursorStatus::default()
+    };
+    for (key, name) in names.iter() {
+        if let Some(value) = cursor_field(&record.position, key) {
+            status.fields.insert(name.to_string(), value);
+        }
+    }
+    if let Some(realtime) =
+        cursor_field(&record.position, "t").and_then(|t| u64::from_str_radix(&t, 16).ok())
+    {
+        status
+            .fields
+            .insert(String::from("realtime-utc"), usec_to_rfc3339(realtime));
+    }
+
+    let mut journal = open_journal(settings, &record.namespace)?;
+    let mut record_usec = None;
+    if record.position == HEAD_POSITION {
+        journal.seek(JournalSeek::Head)?;
+        status.found = true;
+    } else {
+        seek_journal(
+            &mut journal,
+            JournalSeek::Cursor {
+                cursor: record.position.clone(),
+            },
+        )
+        .unwrap_or_default();
+        status.found = journal
+            .test_cursor(record.position.as_str())
+            .unwrap_or(false);
+        if status.found {
+            record_usec = Some(journal.timestamp_usec()?);
+        }
+    }
+
+    journal.seek(JournalSeek::Tail)?;
+    let tail_usec = match journal.previous()? {
+        0 => None,
+        _ => Some(journal.timestamp_usec()?),
+    };
+
+    status.timestamp = record_usec.map(usec_to_rfc3339);
+    status.tail_timestamp = tail_usec.map(usec_to_rfc3339);
+    if let (Some(record_usec), Some(tail_usec)) = (record_usec, tail_usec) {
+        let lag = StdDuration::from_micros(tail_usec.saturating_sub(record_usec));
+        status.lag = Some(format!("{:?}", lag));
+    }
+
+    Ok(status)
+}
+
+// Where the follower should continue after a cursor reset
+pub fn reset_position(
+    settings: &Settings,
+    namespace: &str,
+    to: &str,
+    value: &str,
+) -> Result<String> {
+    let mut journal = open_journal(settings, namespace)?;
+
+    let position = match to {
+        "head" => String::default(),
+        "tail" => seek_journal(&mut journal, JournalSeek::Tail)?,
+        "count" => seek_count(&mut journal, value.parse::<i64>()?)?,
+        "time" => {
+            let time = parse_point_in_time(value)?;
+            let usec =
+                time.timestamp() as u64 * 1_000_000 + u64::from(time.timestamp_subsec_micros());
+            seek_before_time(&mut journal, usec)?
+        }
+        to => failure::bail!("{} is not a valid reset target!", to),
+    };
+
+    if position.is_empty() {
+        Ok(String::from(HEAD_POSITION))
+    } else {
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_fixture(name: &str) -> Journal {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        OpenFilesOptions::default()
+            .open_files(vec![path.as_str()])
+            .unwrap()
+    }
+
+    // Read the messages the way the follower would after the given cursor
+    fn messages_after(position: &str) -> Vec<String> {
+        let mut journal = open_fixture("count.journal");
+        seek_journal(
+            &mut journal,
+            JournalSeek::Cursor {
+                cursor: position.to_string(),
+            },
+        )
+        .unwrap_or_default();
+        let mut messages = vec![];
+        while let Some(record) = journal.next_entry().unwrap() {
+            messages.push(record["MESSAGE"].clone());
+        }
+        messages
+    }
+
+    fn messages_for_count(count: i64) -> Vec<String> {
+        let mut journal = open_fixture("count.journal");
+        messages_after(&seek_count(&mut journal, count).unwrap())
+    }
+
+    #[test]
+    fn count_negative_reads_the_last_records() {
+        let all = messages_after("");
+        assert_eq!(all.len(), 13);
+        assert_eq!(messages_for_count(-1), all[12..].to_vec());
+        assert_eq!(messages_for_count(-3), all[10..].to_vec());
+        assert_eq!(messages_for_count(-12), all[1..].to_vec());
+        assert_eq!(messages_for_count(-13), all);
+    }
+
+    #[test]
+    fn count_negative_longer_than_journal_reads_everything() {
+        assert_eq!(messages_for_count(-14), messages_after(""));
+        assert_eq!(messages_for_count(-1_000_000), messages_after(""));
+    }
+
+    #[test]
+    fn count_positive_passes_over_the_first_records() {
+        let all = messages_after("");
+        assert_eq!(messages_for_count(1), all[1..].to_vec());
+        assert_eq!(messages_for_count(3), all[3..].to_vec());
+        assert_eq!(messages_for_count(12), all[12..].to_vec());
+        assert!(messages_for_count(13).is_empty());
+    }
+
+    #[test]
+    fn count_positive_longer_than_journal_reads_nothing() {
+        assert!(messages_for_count(14).is_empty());
+        assert!(messages_for_count(1_000_000).is_empty());
+    }
+
+    #[test]
+    fn count_zero_reads_only_new_records() {
+        assert!(messages_for_count(0).is_empty());
+    }
+
+    // The cursor of a record, as if its journal file had been vacuumed away
+    fn vacuumed_cursor(index: usize) -> String {
+        let mut journal = open_fixture("count.journal");
+        journal.next_skip(index as u64 + 1).unwrap();
+        let cursor = journal.cursor().unwrap();
+        let seqnum_id = cursor_field(&cursor, "s").unwrap();
+        cursor.replace(&seqnum_id, "00000000000000000000000000000000")
+    }
+
+    fn messages_after_recovery(policy: CursorRecovery, position: &str) -> (String, Vec<String>) {
+        let settings = Settings {
+            cursor_recovery: policy,
+            ..Settings::default()
+        };
+        let mut journal = open_fixture("count.journal");
+        seek_journal(
+            &mut journal,
+            JournalSeek::Cursor {
+                cursor: position.to_string(),
+            },
+        )
+        .unwrap_or_default();
+        assert!(!journal.test_cursor(position).unwrap_or(false));
+        let applied = recover_cursor(&settings, &mut journal, position).unwrap();
+        let mut messages = vec![];
+        while let Some(record) = journal.next_entry().unwrap() {
+            messages.push(record["MESSAGE"].clone());
+        }
+        (applied, messages)
+    }
+
+    #[test]
+    fn vacuumed_cursor_recovers_from_timestamp() {
+        let all = messages_after("");
+        assert_eq!(
+            messages_after_recovery(CursorRecovery::Timestamp, &vacuumed_cursor(4)),
+            (String::from("timestamp"), all[5..].to_vec())
+        );
+    }
+
+    #[test]
+    fn vacuumed_cursor_recovers_from_head_or_tail() {
+        assert_eq!(
+            messages_after_recovery(CursorRecovery::Head, &vacuumed_cursor(4)),
+            (String::from("head"), messages_after(""))
+        );
+        assert_eq!(
+            messages_after_recovery(CursorRecovery::Tail, &vacuumed_cursor(4)),
+            (String::from("tail"), vec![])
+        );
+    }
+
+    #[test]
+    fn cursor_without_timestamp_recovers_from_head() {
+        assert_eq!(
+            messages_after_recovery(CursorRecovery::Timestamp, "not a cursor"),
+            (String::from("head"), messages_after(""))
+        );
+    }
+
+    fn fixture_settings() -> Settings {
+        let path = format!(
+            "{}/tests/fixtures/count.journal",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        Settings {
+            journal_files: vec![path],
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn cursor_reset_to_head_and_tail() {
+        let settings = fixture_settings();
+        assert_eq!(
+            reset_position(&settings, "", "head", "").unwrap(),
+            HEAD_POSITION
+        );
+        let tail = reset_position(&settings, "", "tail", "").unwrap();
+        assert!(messages_after(&tail).is_empty());
+    }
+
+    #[test]
+    fn cursor_reset_to_time_starts_at_that_record() {
+        let settings = fixture_settings();
+        let all = messages_after("");
+        let mut journal = open_fixture("count.journal");
+        journal.next_skip(5).unwrap();
+        let time = usec_to_rfc3339(journal.timestamp_usec().unwrap());
+        let position = reset_position(&settings, "", "time", &time).unwrap();
+        assert_eq!(messages_after(&position), all[4..].to_vec());
+    }
+
+    #[test]
+    fn cursor_reset_to_count_matches_history_count() {
+        let settings = fixture_settings();
+        let position = reset_position(&settings, "", "count", "-3").unwrap();
+        assert_eq!(messages_after(&position), messages_for_count(-3));
+        assert_eq!(
+            reset_position(&settings, "", "count", "-100").unwrap(),
+            HEAD_POSITION
+        );
+    }
+
+    #[test]
+    fn history_absolute_includes_the_first_record_of_the_window() {
+        let settings = Settings {
+            history_type: HistoryType::Absolute,
+            history_absolute: Utc.ymd(2000, 1, 1).and_hms(0, 0, 0),
+            ..fixture_settings()
+        };
+        let mut journal = open_fixture("count.journal");
+        let position = seek_history(&settings, &mut journal).unwrap();
+        assert_eq!(messages_after(&position).len(), 13);
+    }
+
+    #[test]
+    fn history_in_the_future_reads_nothing() {
+        let settings = Settings {
+            history_type: HistoryType::Absolute,
+            history_absolute: Utc.ymd(2099, 1, 1).and_hms(0, 0, 0),
+            ..fixture_settings()
+        };
+        let mut journal = open_fixture("count.journal");
+        let position = seek_history(&settings, &mut journal).unwrap();
+        assert!(!position.is_empty());
+        assert!(messages_after(&position).is_empty());
+
+        let mut journal = open_fixture("count.journal");
+        let position = seek_journal(
+            &mut journal,
+            JournalSeek::ClockRealtime {
+                usec: 4_070_908_800_000_000,
+            },
+        )
+        .unwrap();
+        assert!(messages_after(&position).is_empty());
+    }
+
+    #[test]
+    fn gap_event_carries_the_lost_cursor() {
+        let lost = CursorRecord {
+            position: String::from("s=abc;t=1"),
+            namespace: String::from("tenant"),
+        };
+        let event = gap_event(&lost, "timestamp");
+        assert_eq!(event["journald.gap"], true);
+        assert_eq!(event["journald.cursor"], "s=abc;t=1");
+        assert_eq!(event["journald.namespace"], "tenant");
+        assert_eq!(event["journald.recovery"], "timestamp");
+    }
+}
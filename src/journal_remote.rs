@@ -0,0 +1,172 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use crate::{
+    cursor::CursorRecord,
+    export::{export_fields, write_export_entry},
+    http::HttpClient,
+    pipeline::{Event, Sink},
+    sink::HostRecord,
+    Result,
+};
+
+const UPLOAD_PATH: &str = "/upload";
+
+const CONTENT_TYPE: &str = "application/vnd.fdo.journal";
+
+// Posts batches in the Journal Export Format to /upload, the way
+// systemd-journal-upload does, for systemd-journal-remote --listen-http or
+// --listen-https with host-protocol tls. The cursors of a batch are
+// acknowledged only once the upload is accepted; any other answer is retried.
+pub struct JournalRemoteSink {
+    client: HttpClient,
+    status_line: String,
+}
+
+impl JournalRemoteSink {
+    pub fn new(host: HostRecord) -> JournalRemoteSink {
+        JournalRemoteSink {
+            client: HttpClient::new(host),
+            status_line: String::default(),
+        }
+    }
+}
+
+impl Sink for JournalRemoteSink {
+    fn label(&self) -> String {
+        format!("journal-remote {}", self.client.url(UPLOAD_PATH))
+    }
+
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        for event in batch {
+            write_export_entry(&export_fields(event), &mut bytes);
+        }
+        Ok(bytes)
+    }
+
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        let body = self.encode(batch)?;
+        let response = self.client.request(
+            "POST",
+            UPLOAD_PATH,
+            &[("Content-Type", String::from(CONTENT_TYPE))],
+            &body,
+        )?;
+        self.status_line = response.status_line.clone();
+
+        if !response.is_success() {
+            failure::bail!(
+                "The upload was refused with {}: {}",
+                response.status_line,
+                response.body_text()
+            );
+        }
+
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn http_client(&self) -> Option<&HttpClient> {
+        Some(&self.client)
+    }
+
+    fn acknowledgement(&self) -> String {
+        self.status_line.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{export::read_export_entry, http::serve_requests, settings::HostType};
+
+    use std::{collections::BTreeMap, net::TcpListener};
+
+    fn hello(position: &str) -> (Event, BTreeMap<String, String>) {
+        let mut fields = BTreeMap::new();
+        fields.insert(String::from("__CURSOR"), position.to_string());
+        fields.insert(String::from("MESSAGE"), String::from("hello"));
+        let event = Event {
+            value: serde_json::json!({ "message": "hello" }),
+            cursor: CursorRecord {
+                position: position.to_string(),
+                namespace: String::default(),
+            },
+            fields: fields.clone(),
+        };
+        (event, fields)
+    }
+
+    fn sink(port: u16) -> JournalRemoteSink {
+        JournalRemoteSink::new(HostRecord {
+            host: String::from("127.0.0.1"),
+            port,
+            kind: HostType::JournalRemote,
+            ..HostRecord::default()
+        })
+    }
+
+    #[test]
+    fn batches_are_uploaded_in_the_export_format() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_requests(
+            listener,
+            vec![String::from(
+                "HTTP/1.1 202 Accepted\r\nContent-Length: 3\r\n\r\nOK.",
+            )],
+        );
+
+        let (event, fields) = hello("s=1;i=2");
+        let mut sink = sink(port);
+        assert_eq!(
+            sink.label(),
+            format!("journal-remote http://127.0.0.1:{}/upload", port)
+        );
+        let acknowledged = sink.send(std::slice::from_ref(&event)).unwrap();
+        assert_eq!(acknowledged, vec![event.cursor]);
+        assert_eq!(sink.acknowledgement(), "HTTP/1.1 202 Accepted");
+
+        let requests = server.join().unwrap();
+        let (head, body) = &requests[0];
+        assert_eq!(head[0], "POST /upload HTTP/1.1");
+        assert!(head.contains(&String::from("content-type: application/vnd.fdo.journal")));
+        let mut reader = std::io::Cursor::new(body);
+        assert_eq!(read_export_entry(&mut reader).unwrap(), Some(fields));
+    }
+
+    #[test]
+    fn uploads_that_are_not_accepted_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_requests(
+            listener,
+            vec![
+                String::from("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"),
+                String::from("HTTP/1.1 400 Bad Request\r\nContent-Length: 9\r\n\r\nbad entry"),
+                String::from("HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n"),
+            ],
+        );
+
+        let (event, _) = hello("s=1;i=3");
+        let mut sink = sink(port);
+        assert!(sink.send(std::slice::from_ref(&event)).is_err());
+        let refused = sink.send(std::slice::from_ref(&event)).unwrap_err();
+        assert!(refused.to_string().contains("bad entry"));
+        let acknowledged = sink.send(std::slice::from_ref(&event)).unwrap();
+        assert_eq!(acknowledged, vec![event.cursor]);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,47 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// The pieces of journaldeliver: a Source yields records together with their
+// cursor, Transforms change or drop them and a Sink delivers them in batches,
+// acknowledging the cursors that may be saved.
+
+#![allow(unknown_lints)]
+#![warn(clippy::all)]
+
+#[macro_use]
+extern crate serde_derive;
+
+use failure::Error as FailError;
+
+use std::result::Result as StdResult;
+
+pub mod compress;
+pub mod cursor;
+pub mod elasticsearch;
+pub mod export;
+pub mod fluent;
+pub mod gelf;
+pub mod http;
+pub mod http_sink;
+pub mod journal;
+pub mod journal_remote;
+pub mod loki;
+pub mod memory;
+pub mod msgpack;
+pub mod pipeline;
+pub mod settings;
+pub mod sink;
+pub mod tls;
+
+pub type Result<T> = StdResult<T, FailError>;
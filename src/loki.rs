@@ -0,0 +1,534 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// Pushes to Grafana Loki. The keys named in loki-labels become the labels of
+// a stream, the rest of the record is the line. Each batch is one push with
+// the entries of every stream in time order.
+
+use chrono::Utc;
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use std::collections::BTreeMap;
+
+use crate::{
+    cursor::CursorRecord,
+    http::{refuse_batch, HttpClient, HttpResponse},
+    pipeline::{Event, Sink},
+    settings::{LokiEncoding, LokiLineFormat, Settings},
+    sink::HostRecord,
+    Result,
+};
+
+const PUSH_PATH: &str = "/loki/api/v1/push";
+
+// A record with none of the labels still needs one to be stored
+const FALLBACK_LABEL: (&str, &str) = ("job", "journaldeliver");
+
+// A label of loki-labels, such as unit=systemd-unit
+pub fn parse_label(label: &str) -> Option<(String, String)> {
+    let mut parts = label.splitn(2, '=');
+    let name = parts.next().unwrap_or_default().trim();
+    let key = parts.next().unwrap_or(name).trim();
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(index, character)| match character {
+            'a'..='z' | 'A'..='Z' | '_' => true,
+            '0'..='9' => index > 0,
+            _ => false,
+        });
+    if name.is_empty() || key.is_empty() || !valid {
+        return None;
+    }
+    Some((name.to_string(), key.to_string()))
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|character| character <= ' ' || character == '=' || character == '"')
+    {
+        return value.to_string();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+// The line of a record, without the keys that became labels
+pub fn loki_line(value: &JsonValue, labels: &[(String, String)], format: LokiLineFormat) -> String {
+    let mut rest = value.as_object().cloned().unwrap_or_default();
+    for (_, key) in labels {
+        rest.remove(key);
+    }
+
+    match format {
+        LokiLineFormat::Json => JsonValue::from(rest).to_string(),
+        LokiLineFormat::Logfmt => rest
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    JsonValue::String(text) => text.clone(),
+                    other => other.to_string(),
+                };
+                format!("{}={}", key, logfmt_value(&value))
+            })
+            .collect::<Vec<String>>()
+            .join(" "),
+    }
+}
+
+// Labels in the selector form, {host="web-1", unit="app.service"}
+fn rejects_entries(response: &HttpResponse) -> bool {
+    let reason = response.body_text();
+    response.status == 400
+        && (reason.contains("entry out of order") || reason.contains("too far behind"))
+}
+
+fn selector(labels: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(", "))
+}
+
+// Unsigned LEB128, as protobuf writes lengths and integers
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(out, field << 3 | 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// One stream's worth of entries, each the time in nanoseconds and the line
+struct Stream {
+    labels: BTreeMap<String, String>,
+    entries: Vec<(i64, String)>,
+}
+
+pub struct LokiSink {
+    client: HttpClient,
+    labels: Vec<(String, String)>,
+    line_format: LokiLineFormat,
+    encoding: LokiEncoding,
+    tenant: String,
+    dead_letter: String,
+    status_line: String,
+}
+
+impl LokiSink {
+    pub fn new(host: HostRecord, settings: &Settings) -> LokiSink {
+        LokiSink {
+            client: HttpClient::new(host),
+            labels: settings
+                .loki_labels
+                .iter()
+                .filter_map(|label| parse_label(label))
+                .collect(),
+            line_format: settings.loki_line_format,
+            encoding: settings.loki_encoding,
+            tenant: settings.loki_tenant.clone(),
+            dead_letter: settings.loki_dead_letter.clone(),
+            status_line: String::default(),
+        }
+    }
+
+    // The records of a batch by their labels, in time order within each
+    fn streams(&self, batch: &[Event]) -> Vec<Stream> {
+        let mut streams: BTreeMap<String, Stream> = BTreeMap::new();
+
+        for event in batch {
+            let mut labels = BTreeMap::new();
+            for (name, key) in self.labels.iter() {
+                match event.value.get(key) {
+                    Some(JsonValue::String(text)) => labels.insert(name.clone(), text.clone()),
+                    Some(JsonValue::Null) | None => None,
+                    Some(other) => labels.insert(name.clone(), other.to_string()),
+                };
+            }
+            if labels.is_empty() {
+                labels.insert(FALLBACK_LABEL.0.to_string(), FALLBACK_LABEL.1.to_string());
+            }
+
+            let timestamp = event.timestamp().unwrap_or_else(Utc::now);
+            let line = loki_line(&event.value, &self.labels, self.line_format);
+            streams
+                .entry(selector(&labels))
+                .or_insert_with(|| Stream {
+                    labels,
+                    entries: vec![],
+                })
+                .entries
+                .push((timestamp.timestamp_nanos(), line));
+        }
+
+        let mut streams: Vec<Stream> = streams.into_values().collect();
+        for stream in streams.iter_mut() {
+            stream.entries.sort_by_key(|(nanos, _)| *nanos);
+        }
+        streams
+    }
+
+    // A PushRequest of logproto, before compression
+    fn protobuf(&self, streams: &[Stream]) -> Vec<u8> {
+        let mut request = vec![];
+        for stream in streams {
+            let mut adapter = vec![];
+            write_field(&mut adapter, 1, selector(&stream.labels).as_bytes());
+            for (nanos, line) in stream.entries.iter() {
+                let mut timestamp = vec![];
+                write_varint(&mut timestamp, 1 << 3);
+                write_varint(&mut timestamp, (nanos / 1_000_000_000) as u64);
+                write_varint(&mut timestamp, 2 << 3);
+                write_varint(&mut timestamp, (nanos % 1_000_000_000) as u64);
+                let mut entry = vec![];
+                write_field(&mut entry, 1, &timestamp);
+                write_field(&mut entry, 2, line.as_bytes());
+                write_field(&mut adapter, 2, &entry);
+            }
+            write_field(&mut request, 1, &adapter);
+        }
+        request
+    }
+
+    fn json(&self, streams: &[Stream]) -> Vec<u8> {
+        let streams: Vec<JsonValue> = streams
+            .iter()
+            .map(|stream| {
+                let labels: JsonMap<String, JsonValue> = stream
+                    .labels
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.clone().into()))
+                    .collect();
+                let values: Vec<JsonValue> = stream
+                    .entries
+                    .iter()
+                    .map(|(nanos, line)| serde_json::json!([nanos.to_string(), line]))
+                    .collect();
+                serde_json::json!({ "stream": labels, "values": values })
+            })
+            .collect();
+        serde_json::json!({ "streams": streams })
+            .to_string()
+            .into_bytes()
+    }
+}
+
+impl Sink for LokiSink {
+    fn label(&self) -> String {
+        format!("loki {}", self.client.url(PUSH_PATH))
+    }
+
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let streams = self.streams(batch);
+        Ok(match self.encoding {
+            LokiEncoding::Protobuf => {
+                snap::raw::Encoder::new().compress_vec(&self.protobuf(&streams))?
+            }
+            LokiEncoding::Json => self.json(&streams),
+        })
+    }
+
+    // Only a push Loki rejects for its entries, such as one out of order or
+    // too far behind, would come back the same and goes to the dead letter
+    // file instead. Anything else, a missing tenant or credentials included,
+    // is tried again.
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        let body = self.encode(batch)?;
+        let mut headers = vec![(
+            "Content-Type",
+            String::from(match self.encoding {
+                LokiEncoding::Protobuf => "application/x-protobuf",
+                LokiEncoding::Json => "application/json",
+            }),
+        )];
+        if self.encoding == LokiEncoding::Protobuf {
+            headers.push(("Content-Encoding", String::from("snappy")));
+        }
+        if !self.tenant.is_empty() {
+            headers.push(("X-Scope-OrgID", self.tenant.clone()));
+        }
+
+        let response = self.client.request("POST", PUSH_PATH, &headers, &body)?;
+        self.status_line = response.status_line.clone();
+
+        if !self.dead_letter.is_empty() && rejects_entries(&response) {
+            refuse_batch(&self.dead_letter, &self.label(), batch, &response)?;
+        } else if !response.is_success() {
+            failure::bail!(
+                "The push was refused with {}: {}",
+                response.status_line,
+                response.body_text()
+            );
+        }
+
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn http_client(&self) -> Option<&HttpClient> {
+        Some(&self.client)
+    }
+
+    fn acknowledgement(&self) -> String {
+        self.status_line.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{http::serve_requests, settings::HostType};
+
+    use std::net::TcpListener;
+
+    fn read_varint(data: &[u8], position: &mut usize) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = data[*position];
+            *position += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    // The fields of a protobuf message, varints as their value and
+    // length-delimited fields as their bytes
+    fn read_fields(data: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        let mut fields = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            let key = read_varint(data, &mut position);
+            match key & 7 {
+                0 => fields.push((key >> 3, read_varint(data, &mut position), vec![])),
+                2 => {
+                    let length = read_varint(data, &mut position) as usize;
+                    let bytes = data[position..position + length].to_vec();
+                    position += length;
+                    fields.push((key >> 3, 0, bytes));
+                }
+                wire => panic!("wire type {} is not written", wire),
+            }
+        }
+        fields
+    }
+
+    // A PushRequest back as its streams of labels and entries
+    fn decode_push(request: &[u8]) -> Vec<(String, Vec<(i64, String)>)> {
+        read_fields(request)
+            .iter()
+            .map(|(_, _, stream)| {
+                let mut labels = String::default();
+                let mut entries = vec![];
+                for (field, _, bytes) in read_fields(stream) {
+                    if field == 1 {
+                        labels = String::from_utf8(bytes).unwrap();
+                        continue;
+                    }
+                    let entry = read_fields(&bytes);
+                    let timestamp = read_fields(&entry[0].2);
+                    let nanos = timestamp[0].1 as i64 * 1_000_000_000 + timestamp[1].1 as i64;
+                    entries.push((nanos, String::from_utf8(entry[1].2.clone()).unwrap()));
+                }
+                (labels, entries)
+            })
+            .collect()
+    }
+
+    fn event(unit: &str, realtime: &str, message: &str) -> Event {
+        let mut fields = BTreeMap::new();
+        fields.insert(String::from("__REALTIME_TIMESTAMP"), realtime.to_string());
+        Event {
+            value: serde_json::json!({
+                "message": message,
+                "systemd-unit": unit,
+                "hostname": "web-1",
+            }),
+            cursor: CursorRecord::default(),
+            fields,
+        }
+    }
+
+    fn sink(encoding: LokiEncoding, line_format: LokiLineFormat) -> LokiSink {
+        let settings = Settings {
+            loki_encoding: encoding,
+            loki_line_format: line_format,
+            ..Settings::default()
+        };
+        LokiSink::new(HostRecord::default(), &settings)
+    }
+
+    #[test]
+    fn labels_and_lines() {
+        assert_eq!(
+            parse_label("unit=systemd-unit"),
+            Some((String::from("unit"), String::from("systemd-unit")))
+        );
+        assert_eq!(
+            parse_label("hostname"),
+            Some((String::from("hostname"), String::from("hostname")))
+        );
+        assert_eq!(parse_label("2fa=x"), None);
+
+        let value = serde_json::json!({ "message": "two words", "pid": "7", "unit": "a" });
+        let labels = vec![(String::from("unit"), String::from("unit"))];
+        assert_eq!(
+            loki_line(&value, &labels, LokiLineFormat::Logfmt),
+            "message=\"two words\" pid=7"
+        );
+        assert_eq!(
+            loki_line(&value, &labels, LokiLineFormat::Json),
+            "{\"message\":\"two words\",\"pid\":\"7\"}"
+        );
+    }
+
+    #[test]
+    fn streams_are_grouped_and_in_time_order() {
+        let batch = [
+            event("app.service", "2000000", "later"),
+            event("db.service", "1500000", "db"),
+            event("app.service", "1000001", "earlier"),
+        ];
+        let body = sink(LokiEncoding::Json, LokiLineFormat::Logfmt)
+            .encode(&batch)
+            .unwrap();
+        let push: JsonValue = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            push["streams"][0]["stream"],
+            serde_json::json!({ "host": "web-1", "unit": "app.service" })
+        );
+        assert_eq!(
+            push["streams"][0]["values"],
+            serde_json::json!([
+                ["1000001000", "message=earlier"],
+                ["2000000000", "message=later"]
+            ])
+        );
+        assert_eq!(push["streams"][1]["stream"]["unit"], "db.service");
+
+        let protobuf = sink(LokiEncoding::Protobuf, LokiLineFormat::Json);
+        let request = protobuf.protobuf(&protobuf.streams(&batch[1..2]));
+        let labels = b"{host=\"web-1\", unit=\"db.service\"}";
+        let mut expected = vec![0x0a, 65, 0x0a, labels.len() as u8];
+        expected.extend_from_slice(labels);
+        expected.extend_from_slice(&[0x12, 28, 0x0a, 8, 0x08, 1, 0x10]);
+        expected.extend_from_slice(&[0x80, 0xca, 0xb5, 0xee, 0x01]);
+        expected.extend_from_slice(&[0x12, 16]);
+        expected.extend_from_slice(b"{\"message\":\"db\"}");
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn protobuf_pushes_decode_to_the_streams() {
+        let batch = [
+            event("app.service", "2000000", "later"),
+            event("db.service", "1500000", "db"),
+            event("app.service", "1000001", "earlier"),
+        ];
+        let protobuf = sink(LokiEncoding::Protobuf, LokiLineFormat::Logfmt);
+        let body = protobuf.encode(&batch).unwrap();
+        let request = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(request, protobuf.protobuf(&protobuf.streams(&batch)));
+
+        assert_eq!(
+            decode_push(&request),
+            vec![
+                (
+                    String::from("{host=\"web-1\", unit=\"app.service\"}"),
+                    vec![
+                        (1_000_001_000, String::from("message=earlier")),
+                        (2_000_000_000, String::from("message=later")),
+                    ]
+                ),
+                (
+                    String::from("{host=\"web-1\", unit=\"db.service\"}"),
+                    vec![(1_500_000_000, String::from("message=db"))]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_entries_are_dead_lettered_and_other_refusals_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_requests(
+            listener,
+            vec![
+                String::from(
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 18\r\n\r\nentry out of order",
+                ),
+                String::from("HTTP/1.1 401 Unauthorized\r\nContent-Length: 9\r\n\r\nno org id"),
+                String::from("HTTP/1.1 400 Bad Request\r\nContent-Length: 9\r\n\r\nno org id"),
+                String::from("HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n"),
+            ],
+        );
+
+        let dead_letter = std::env::temp_dir().join(format!(
+            "journaldeliver-loki-dead-letter-{}.ndjson",
+            std::process::id()
+        ));
+        let settings = Settings {
+            loki_encoding: LokiEncoding::Json,
+            loki_dead_letter: dead_letter.to_string_lossy().to_string(),
+            ..Settings::default()
+        };
+        let mut sink = LokiSink::new(
+            HostRecord {
+                host: String::from("127.0.0.1"),
+                port,
+                kind: HostType::Loki,
+                ..HostRecord::default()
+            },
+            &settings,
+        );
+        let batch = [event("app.service", "1000001", "hello")];
+        assert_eq!(sink.send(&batch).unwrap().len(), 1);
+        let error = sink.send(&batch).unwrap_err().to_string();
+        assert!(error.contains("401 Unauthorized"), "{}", error);
+        assert!(sink.send(&batch).is_err());
+        let error = sink.send(&batch).unwrap_err().to_string();
+        assert!(error.contains("502 Bad Gateway"), "{}", error);
+        assert_eq!(server.join().unwrap().len(), 4);
+
+        let letters: Vec<JsonValue> = std::fs::read_to_string(&dead_letter)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_file(&dead_letter).unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0]["status"], 400);
+        assert_eq!(letters[0]["error"], "entry out of order");
+        assert_eq!(letters[0]["document"]["message"], "hello");
+    }
+}
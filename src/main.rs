@@ -18,194 +18,96 @@
 #[macro_use]
 extern crate serde_derive;
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::Utc;
 
-use clap::{app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg};
-
-use config::{Config, File as ConfigFile, FileFormat, Value as ConfigValue};
-
-use failure::Error as FailError;
-
-use parse_duration::parse as parse_duration;
-
-use serde_json::{Map as JsonMap, Value as JsonValue};
+use clap::{
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, AppSettings, Arg,
+    SubCommand,
+};
 
-use serde_yaml::{
-    from_str as yaml_from_str, to_string as to_yaml_string, to_writer as yaml_to_writer,
-    Value as YamlValue,
+use config::{Config, Value as ConfigValue};
+
+use journaldeliver::{
+    cursor::{
+        read_cursor_state, read_write_cursor_thread, write_cursor_state, CursorRecord, CursorState,
+    },
+    export::forward_export,
+    journal::{
+        cursor_status, follow_journal, get_namespaces, open_journal, reset_position, seek_history,
+        seek_journal, CursorStatus,
+    },
+    pipeline::{run_sink, Event, ExcludeRecord, PrintRecord, Transform},
+    settings::{
+        check_config, config_json_schema, config_with_origins, get_config_layers,
+        get_history_until, merge_config_layers, unknown_config_keys, ConfigProblem, RunMode,
+        Settings,
+    },
+    sink::{sink_from_settings, test_destination, DryRunSink},
+    Result,
 };
 
+use serde_yaml::{from_str as yaml_from_str, to_string as to_yaml_string};
+
 use std::{
     collections::BTreeMap,
-    fs::OpenOptions,
-    io::{Read, Seek, SeekFrom, Write},
-    iter::FromIterator,
-    net::{IpAddr, SocketAddr, TcpStream},
-    path::Path,
-    result::Result as StdResult,
+    ffi::OsString,
+    io::Read,
     sync::mpsc,
     thread,
     time::{Duration as StdDuration, Instant as StdInstant},
 };
 
 use nix::{
-    libc::{c_int, getrusage, rusage, timeval, RUSAGE_SELF},
     sys::wait::{waitpid, WaitPidFlag, WaitStatus::*},
     unistd::{fork, ForkResult, Pid},
 };
 
-use systemd::journal::{Journal, JournalFiles, JournalSeek};
+use systemd::journal::JournalSeek;
 
-type Result<T> = StdResult<T, FailError>;
-type InitialTuple = (CursorRecord, Config);
+type InitialTuple = (CursorState, Settings);
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
-struct CursorRecord {
-    position: String,
-}
+fn test_destination_command(settings: &Settings) -> Result<()> {
+    let report = test_destination(settings);
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
-struct HostRecord {
-    host: String,
-    port: u16,
-    protocol: String,
-}
+    println!("{}", to_yaml_string(&report)?);
 
-fn send_json_to_remote_host(
-    connection: &HostRecord,
-    journal_entry: &mpsc::Receiver<(JsonValue, CursorRecord)>,
-    cursor_sender: &mpsc::SyncSender<CursorRecord>,
-) {
-    loop {
-        let ip: IpAddr = connection
-            .host
-            .parse()
-            .unwrap_or_else(|_| "127.0.0.1".parse().unwrap());
-        let address = SocketAddr::new(ip, connection.port);
-        let stream_result = TcpStream::connect(&address);
-        if let Ok(mut stream) = stream_result {
-            loop {
-                let entry_result = journal_entry.recv_timeout(StdDuration::from_millis(7654));
-                match entry_result {
-                    Ok((value, cursor)) => {
-                        let entry_json_string = value.to_string();
-                        let write_result =
-                            stream.write_fmt(format_args!("{}\n", entry_json_string));
-                        match write_result {
-                            Ok(()) => cursor_sender.send(cursor).unwrap_or_default(),
-                            _ => {
-                                break;
-                            }
-                        }
-                    }
-                    _ => {
-                        thread::sleep(StdDuration::from_millis(1235));
-                    }
-                }
-                let io_error = stream.take_error();
-                match io_error {
-                    Ok(..) => continue,
-                    Err(error) => {
-                        eprintln!("{:#?}", error);
-                        break;
-                    }
-                }
-            }
-        }
+    if let Some(error) = report.error {
+        failure::bail!("{}", error);
     }
+
+    Ok(())
 }
 
-fn read_write_cursor_thread(path: &str, cursor_receiver: &mpsc::Receiver<CursorRecord>) {
-    let mut pit = StdInstant::now();
-    let mut written_cursor_value = CursorRecord::default();
-    // Open cursor file
-    loop {
-        if let Ok(local_cursor_value) = cursor_receiver.recv() {
-            if pit.elapsed() > StdDuration::from_millis(1234)
-                && written_cursor_value != local_cursor_value
-            {
-                let mut cursor_file = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(&path)
-                    .unwrap_or_else(|error| {
-                        panic!("{:#?}\nwhile trying to open file: {}", error, &path)
-                    });
-                cursor_file.seek(SeekFrom::Start(0)).unwrap_or_default();
-                cursor_file.seek(SeekFrom::Start(0)).unwrap_or_default();
-                cursor_file.set_len(0).unwrap_or_default();
-                yaml_to_writer(&cursor_file, &local_cursor_value).unwrap_or(());
-                cursor_file.write(b"\n").unwrap_or_default();
-                pit = StdInstant::now();
-                written_cursor_value = local_cursor_value;
-            }
+// Report every problem in the config, failing when there are any
+fn check_config_command(command_line_args: Config) -> Result<()> {
+    let layers = get_config_layers(command_line_args)?;
+    let config = merge_config_layers(layers.clone())?;
+    let mut problems = check_config(&layers, &config);
+
+    if problems.is_empty() {
+        if let Err(error) = config.clone().try_into::<Settings>() {
+            problems.push(ConfigProblem {
+                origin: String::from("config"),
+                key: String::default(),
+                message: error.to_string(),
+            });
         }
     }
-}
-
-fn get_configs(command_line_args: Config) -> Result<Config> {
-    // Load the default config file
-    let default_yaml_config = include_str!("../configs/defaults.yaml");
-
-    // Set to collect active config files
-    let active_paths: BTreeMap<String, isize>;
 
-    // Get the config paths that work
-    {
-        // Position in list
-        let mut pos: isize = 0;
-
-        // Create an empty config
-        let mut config = Config::default();
-
-        // Merge the default config with the command line args
-        config
-            .merge(ConfigFile::from_str(default_yaml_config, FileFormat::Yaml))?
-            .merge(command_line_args.clone())?;
-
-        active_paths = config
-            .get_array("configs")?
-            .into_iter()
-            .map(|config_file| {
-                (config_file.try_into::<String>().unwrap(), {
-                    pos += 1;
-                    pos
-                })
-            })
-            .collect::<BTreeMap<String, isize>>()
-            .into_iter()
-            .filter(|(path, _)| Path::new(&path).exists())
-            .collect::<BTreeMap<String, isize>>();
+    for warning in unknown_config_keys(&layers).iter() {
+        eprintln!("{}: {}: {}", warning.origin, warning.key, warning.message);
     }
 
-    // Compose final merged config
-    let mut config = Config::default();
-
-    config.merge(ConfigFile::from_str(default_yaml_config, FileFormat::Yaml))?;
-
-    let mut ordered_path_list = Vec::from_iter(active_paths.into_iter());
-
-    ordered_path_list.sort_by(|&(_, a), &(_, b)| a.cmp(&b));
-
-    let mut used_path: Vec<String> = vec![];
-
-    for (path, _) in ordered_path_list.into_iter() {
-        config.merge(ConfigFile::with_name(&path))?;
-
-        used_path.push(path);
+    for problem in problems.iter() {
+        eprintln!("{}: {}: {}", problem.origin, problem.key, problem.message);
     }
 
-    config.merge(command_line_args)?.set(
-        "configs",
-        ConfigValue::from(
-            used_path
-                .into_iter()
-                .map(ConfigValue::from)
-                .collect::<Vec<ConfigValue>>(),
-        ),
-    )?;
+    if !problems.is_empty() {
+        failure::bail!("Found {} problems in the config", problems.len());
+    }
 
-    Ok(config)
+    println!("The config is valid");
+    Ok(())
 }
 
 // Get Command Line Arguments
@@ -227,15 +129,29 @@ fn get_command_line_args() -> Result<Config> {
          Arg::with_name("daemon")
             .long("daemon")
             .short("d")
-            .required_unless_one(&["foreground", "print-config", "list-config-files"])
-            .conflicts_with_all(&["foreground", "print-config", "list-config-files"])
+            .required_unless_one(&["foreground", "oneshot", "dry-run", "print-config", "list-config-files", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["foreground", "oneshot", "dry-run", "print-config", "list-config-files", "check-config"])
             .help("Run the application in the background."),
          Arg::with_name("foreground")
             .long("foreground")
             .short("f")
-            .required_unless_one(&["daemon", "print-config", "list-config-files"])
-            .conflicts_with_all(&["daemon", "print-config", "list-config-files"])
+            .required_unless_one(&["daemon", "oneshot", "dry-run", "print-config", "list-config-files", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "oneshot", "dry-run", "print-config", "list-config-files", "check-config"])
             .help("Run the application in the foreground."),
+         Arg::with_name("oneshot")
+            .long("oneshot")
+            .short("o")
+            .required_unless_one(&["daemon", "foreground", "dry-run", "print-config", "list-config-files", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "foreground", "dry-run", "print-config", "list-config-files", "check-config"])
+            .help("Forward the selected history once, print a summary and exit without saving the cursor."),
+         Arg::with_name("dry-run")
+            .long("dry-run")
+            .required_unless_one(&["daemon", "foreground", "oneshot", "print-config", "list-config-files", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "foreground", "oneshot", "print-config", "list-config-files", "check-config"])
+            .help(
+               "Print the bytes the next run would send to each destination, from the saved cursor \
+                up to now or --history-until, without connecting or saving the cursor.",
+            ),
          Arg::with_name("verbose")
             .long("verbose")
             .short("v")
@@ -248,14 +164,14 @@ fn get_command_line_args() -> Result<Config> {
             .visible_alias("time")
             .alias("hd")
             .takes_value(true)
-            .conflicts_with_all(&["history-absolute", "history-count"])
+            .conflicts_with_all(&["history-absolute", "history-count", "history-boot"])
             .help("How much history should be pre-loaded counting back from now."),
          Arg::with_name("history-absolute")
             .long("history-absolute")
             .visible_alias("absolute")
             .alias("ha")
             .takes_value(true)
-            .conflicts_with_all(&["history-duration", "history-count"])
+            .conflicts_with_all(&["history-duration", "history-count", "history-boot"])
             .help(
                "How much history should be pre-loaded starting at some absolute point in time. \
                 (YYYY-MM-DD T HH:mm:SS + TZ)",
@@ -266,22 +182,57 @@ fn get_command_line_args() -> Result<Config> {
             .alias("hc")
             .takes_value(true)
             .allow_hyphen_values(true)
-            .conflicts_with_all(&["history-duration", "history-absolute"])
+            .conflicts_with_all(&["history-duration", "history-absolute", "history-boot"])
             .help("How much history should be pre-loaded with this number of previous records."),
+         Arg::with_name("history-boot")
+            .long("history-boot")
+            .visible_alias("boot")
+            .alias("hb")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .conflicts_with_all(&["history-duration", "history-absolute", "history-count"])
+            .help(
+               "Pre-load history from the start of a boot, either 0 for the latest boot, \
+                -1 for the one before it and so on, or a boot id.",
+            ),
+         Arg::with_name("history-until")
+            .long("history-until")
+            .visible_alias("until")
+            .alias("hu")
+            .takes_value(true)
+            .conflicts_with_all(&["daemon", "foreground"])
+            .help(
+               "Where a --oneshot or --dry-run run stops, either 'now', a duration counting back from now \
+                or an absolute point in time. (YYYY-MM-DD T HH:mm:SS + TZ)",
+            ),
          Arg::with_name("print-config")
             .long("print-config")
             .alias("pc")
             .visible_alias("print")
-            .required_unless_one(&["daemon", "foreground", "list-config-files"])
-            .conflicts_with_all(&["daemon", "foreground", "list-config-files"])
+            .required_unless_one(&["daemon", "foreground", "oneshot", "dry-run", "list-config-files", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "foreground", "oneshot", "dry-run", "list-config-files", "check-config"])
             .help("Print the merged config used by this application."),
+         Arg::with_name("print-config-format")
+            .long("print-config-format")
+            .visible_alias("format")
+            .takes_value(true)
+            .possible_values(&["yaml", "json", "toml"])
+            .requires("print-config")
+            .help("Print the merged config as YAML, JSON or TOML, each value with the file it came from."),
          Arg::with_name("list-config-files")
             .long("list-config-files")
             .alias("lcf")
             .visible_alias("list")
-            .required_unless_one(&["daemon", "foreground", "print-config"])
-            .conflicts_with_all(&["daemon", "foreground", "print-config"])
+            .required_unless_one(&["daemon", "foreground", "oneshot", "dry-run", "print-config", "check-config", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "foreground", "oneshot", "dry-run", "print-config", "check-config"])
             .help("List the config files used by this application."),
+         Arg::with_name("check-config")
+            .long("check-config")
+            .alias("cc")
+            .visible_alias("check")
+            .required_unless_one(&["daemon", "foreground", "oneshot", "dry-run", "print-config", "list-config-files", "journal-export", "stdin"])
+            .conflicts_with_all(&["daemon", "foreground", "oneshot", "dry-run", "print-config", "list-config-files"])
+            .help("Check the merged config, report every problem with where it came from and exit."),
          Arg::with_name("last-cursor-location")
             .long("last-cursor-location")
             .alias("lcl")
@@ -289,6 +240,62 @@ fn get_command_line_args() -> Result<Config> {
             .short("l")
             .takes_value(true)
             .help("Path to the yaml file containing cursor of the last message passed"),
+         Arg::with_name("journal-source")
+            .long("journal-source")
+            .visible_alias("source")
+            .takes_value(true)
+            .possible_values(&["all", "system", "user"])
+            .help("Which journals to read: the system journal, the user journals or all."),
+         Arg::with_name("journal-runtime-only")
+            .long("journal-runtime-only")
+            .visible_alias("runtime-only")
+            .help("Only read the volatile journal files kept under /run."),
+         Arg::with_name("journal-local-only")
+            .long("journal-local-only")
+            .visible_alias("local-only")
+            .help("Only read journal files created on this machine, skipping merged remote ones."),
+         Arg::with_name("journal-directory")
+            .long("journal-directory")
+            .visible_alias("directory")
+            .short("D")
+            .takes_value(true)
+            .conflicts_with("journal-files")
+            .help("Read the journal files found in this directory instead of the system default."),
+         Arg::with_name("journal-export")
+            .long("journal-export")
+            .visible_alias("export")
+            .takes_value(true)
+            .conflicts_with_all(&["daemon", "foreground", "stdin"])
+            .help(
+               "Forward the records of a Journal Export Format file, such as the output of \
+                journalctl -o export, instead of reading a journal and exit at its end.",
+            ),
+         Arg::with_name("stdin")
+            .long("stdin")
+            .conflicts_with_all(&["daemon", "foreground", "journal-export"])
+            .help("Forward the Journal Export Format read from standard input, the same as --journal-export -."),
+         Arg::with_name("journal-files")
+            .long("journal-files")
+            .visible_alias("file")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .conflicts_with("journal-directory")
+            .help("Read only these journal files instead of the system default."),
+         Arg::with_name("namespaces")
+            .long("namespaces")
+            .visible_alias("namespace")
+            .short("n")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("The journal namespaces to read, '*' for all or '+name' to include the default."),
+         Arg::with_name("cursor-recovery")
+            .long("cursor-recovery")
+            .alias("cr")
+            .takes_value(true)
+            .possible_values(&["head", "tail", "timestamp"])
+            .help("Where to continue when the last cursor is no longer in the journal."),
          Arg::with_name("host-name")
             .long("host-name")
             .visible_alias("hn")
@@ -315,64 +322,151 @@ fn get_command_line_args() -> Result<Config> {
             .visible_alias("ht")
             .short("t")
             .takes_value(true)
-            .possible_values(&["filebeat"])
+            .possible_values(&[
+                "filebeat",
+                "journal-remote",
+                "gelf",
+                "fluent-forward",
+                "elasticsearch",
+                "loki",
+                "http",
+            ])
             .help("The type of the remote host to send data too."),
          Arg::with_name("host-protocol")
             .long("host-protocol")
             .visible_alias("pr")
             .short("P")
-            .possible_values(&["tcp", "udp"])
+            .possible_values(&["tcp", "udp", "tls"])
             .takes_value(true)
             .help("The host protocol to use."),
       ])
+      .setting(AppSettings::SubcommandsNegateReqs)
+      .subcommand(
+         SubCommand::with_name("test-destination")
+            .about("Connect to the configured host, send a marked test record and report how it went."),
+      )
+      .subcommand(
+         SubCommand::with_name("schema")
+            .about("Print the JSON Schema of the config files, for editors that validate YAML."),
+      )
+      .subcommand(
+         SubCommand::with_name("cursor")
+            .about("Inspect or change the saved cursor, best done while the service is stopped.")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(
+               Arg::with_name("namespace")
+                  .long("namespace")
+                  .short("n")
+                  .takes_value(true)
+                  .global(true)
+                  .help("The journal namespace of the cursor, the default namespace if not given."),
+            )
+            .subcommand(
+               SubCommand::with_name("show")
+                  .about("Show the saved cursors, the records they point to and the lag behind the tail."),
+            )
+            .subcommand(
+               SubCommand::with_name("reset")
+                  .about("Move the saved cursor to the head, the tail, a point in time or a record count.")
+                  .arg(
+                     Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&["head", "tail", "time", "count"]),
+                  )
+                  .arg(
+                     Arg::with_name("value")
+                        .index(1)
+                        .allow_hyphen_values(true)
+                        .required_ifs(&[("to", "time"), ("to", "count")])
+                        .help("The time or the record count, as for --history-absolute and --history-count."),
+                  ),
+            )
+            .subcommand(
+               SubCommand::with_name("set")
+                  .about("Save the given cursor.")
+                  .arg(Arg::with_name("value").index(1).required(true)),
+            )
+            .subcommand(
+               SubCommand::with_name("export")
+                  .about("Print the saved cursors so they can be imported elsewhere."),
+            )
+            .subcommand(
+               SubCommand::with_name("import")
+                  .about("Replace the saved cursors with exported ones read from a file or stdin.")
+                  .arg(Arg::with_name("value").index(1)),
+            ),
+      )
       .get_matches();
 
+    if let ("schema", Some(_)) = app_matches.subcommand() {
+        config.set("print-schema", ConfigValue::from(true))?;
+    }
+
+    if let ("test-destination", Some(_)) = app_matches.subcommand() {
+        config.set("test-destination", ConfigValue::from(true))?;
+    }
+
+    // Process the cursor subcommand, if any
+    if let ("cursor", Some(cursor_matches)) = app_matches.subcommand() {
+        if let (command, Some(command_matches)) = cursor_matches.subcommand() {
+            config.set("cursor-command", ConfigValue::from(command))?;
+            for arg_name in &["namespace", "to", "value"] {
+                if let Some(value) = command_matches.value_of(arg_name) {
+                    config.set(&format!("cursor-{}", arg_name), ConfigValue::from(value))?;
+                }
+            }
+        }
+    }
+
     // Process all the arguments presented
     for (arg_name, arg_value) in app_matches.args.into_iter() {
         let vals = &arg_value.vals;
 
+        // A clap default must not hide the config files and the environment
+        if arg_value.occurs == 0 {
+            continue;
+        }
+
         #[allow(clippy::get_unwrap)]
         match arg_name {
             "verbose" => {
-                config.set(
-                    arg_name,
-                    ConfigValue::from(
-                        vals.get(0)
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string()
-                            .parse::<i64>()?,
-                    ),
-                )?;
+                config.set(arg_name, ConfigValue::from(int_arg(arg_name, vals)?))?;
             }
             "host-port" => {
-                config.set(
-                    arg_name,
-                    ConfigValue::from(
-                        vals.get(0)
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string()
-                            .parse::<i64>()?,
-                    ),
-                )?;
+                config.set(arg_name, ConfigValue::from(int_arg(arg_name, vals)?))?;
             }
-            "list-config-files" | "print-config" => {
+            "list-config-files"
+            | "print-config"
+            | "check-config"
+            | "journal-runtime-only"
+            | "journal-local-only" => {
                 config.set(arg_name, ConfigValue::from(true))?;
             }
-            "daemon" | "foreground" => {
+            "stdin" => {
+                config.set("journal-export", ConfigValue::from("-"))?;
+            }
+            "daemon" | "foreground" | "oneshot" | "dry-run" => {
                 config.set("run-mode", ConfigValue::from(arg_name.to_string()))?;
             }
-            "host-name" | "host-type" | "host-protocol" | "last-cursor-location" => {
+            "host-name"
+            | "host-type"
+            | "host-protocol"
+            | "last-cursor-location"
+            | "journal-source"
+            | "journal-directory"
+            | "journal-export"
+            | "print-config-format"
+            | "history-until"
+            | "cursor-recovery" => {
                 config.set(
                     arg_name,
-                    ConfigValue::from(vals.get(0).unwrap().to_str().unwrap()),
+                    ConfigValue::from(vals.first().unwrap().to_str().unwrap()),
                 )?;
             }
 
-            "configs" => {
+            "configs" | "journal-files" | "namespaces" => {
                 config.set(
                     arg_name,
                     ConfigValue::from(
@@ -386,7 +480,7 @@ fn get_command_line_args() -> Result<Config> {
                 config
                     .set(
                         arg_name,
-                        ConfigValue::from(vals.get(0).unwrap().to_str().unwrap()),
+                        ConfigValue::from(vals.first().unwrap().to_str().unwrap()),
                     )?
                     .set("history-type", ConfigValue::from("duration"))?;
             }
@@ -394,30 +488,28 @@ fn get_command_line_args() -> Result<Config> {
                 config
                     .set(
                         arg_name,
-                        ConfigValue::from(vals.get(0).unwrap().to_str().unwrap()),
+                        ConfigValue::from(vals.first().unwrap().to_str().unwrap()),
                     )?
                     .set("history-type", ConfigValue::from("absolute"))?;
             }
-            "history-count" => {
+            "history-boot" => {
                 config
                     .set(
                         arg_name,
-                        ConfigValue::from(
-                            vals.get(0)
-                                .unwrap()
-                                .to_str()
-                                .unwrap()
-                                .parse::<i64>()
-                                .unwrap(),
-                        ),
+                        ConfigValue::from(vals.first().unwrap().to_str().unwrap()),
                     )?
+                    .set("history-type", ConfigValue::from("boot"))?;
+            }
+            "history-count" => {
+                config
+                    .set(arg_name, ConfigValue::from(int_arg(arg_name, vals)?))?
                     .set("history-type", ConfigValue::from("count"))?;
             }
-            arg_name => panic!(
-            "\nOh Shoot!\nI forgot to write code to process '{}' having the value of:- \n{:#?} \
-             at ",
-            arg_name, arg_value
-         ),
+            arg_name => failure::bail!(
+                "The argument '{}' having the value of {:?} is not handled",
+                arg_name,
+                arg_value.vals
+            ),
         }
     }
 
@@ -425,12 +517,38 @@ fn get_command_line_args() -> Result<Config> {
     Ok(config)
 }
 
-fn initialize_the_environment() -> Result<InitialTuple> {
+// The value of a numeric argument, a typo is a config error and not a panic
+fn int_arg(arg_name: &str, vals: &[OsString]) -> Result<i64> {
+    let value = vals
+        .first()
+        .and_then(|value| value.to_str())
+        .unwrap_or_default();
+
+    value
+        .parse::<i64>()
+        .map_err(|_| failure::format_err!("{} is not a valid {}!", value, arg_name))
+}
+
+// Nothing is returned when a mode that only reports or edits something is done
+fn initialize_the_environment() -> Result<Option<InitialTuple>> {
     let command_line_args = get_command_line_args()?;
 
-    let config = get_configs(command_line_args)?;
+    if command_line_args.get_bool("print-schema").unwrap_or(false) {
+        println!("{}", serde_json::to_string_pretty(&config_json_schema())?);
+
+        return Ok(None);
+    }
+
+    if command_line_args.get_bool("check-config").unwrap_or(false) {
+        check_config_command(command_line_args)?;
+
+        return Ok(None);
+    }
+
+    let layers = get_config_layers(command_line_args)?;
+    let config = merge_config_layers(layers.clone())?;
     let verbose = config.get_int("verbose").unwrap_or(0);
-    let mut local_cursor_value = CursorRecord::default();
+    let mut local_cursor_state = CursorState::default();
 
     if verbose >= 5 {
         eprintln!("{:#?}", config);
@@ -438,7 +556,7 @@ fn initialize_the_environment() -> Result<InitialTuple> {
 
     if config.get_bool("list-config-files").unwrap_or(false) {
         for filename in config.get_array("configs").unwrap_or_default().into_iter() {
-            eprintln!(
+            println!(
                 "{}",
                 filename
                     .try_into::<String>()
@@ -446,129 +564,279 @@ fn initialize_the_environment() -> Result<InitialTuple> {
             );
         }
 
-        failure::bail!("Done");
+        return Ok(None);
     }
 
     if config.get_bool("print-config").unwrap_or(false) {
-        println!("{}", to_yaml_string(&config.try_into::<YamlValue>()?)?);
+        let format = config.get_str("print-config-format")?;
+        print!("{}", config_with_origins(&layers, &config, &format)?);
 
-        failure::bail!("Done");
+        return Ok(None);
+    }
+
+    let settings = Settings::from_config(&layers, &config)?;
+
+    if verbose >= 1 {
+        for warning in unknown_config_keys(&layers) {
+            eprintln!(
+                " !! {}: {}: {}",
+                warning.origin, warning.key, warning.message
+            );
+        }
     }
-    let mut journal = Journal::open(JournalFiles::All, false, false)?;
-    match config.get_str("history-type")?.as_str() {
-        "duration" => {
-            let duration = Duration::from_std(parse_duration(
-                config.get_str("history-duration")?.as_str(),
-            )?)?;
-
-            if verbose > 1 {
-                eprintln!(" .. Seek Duration: {:?}", duration);
-            }
 
-            if duration != Duration::seconds(0) {
-                let now: DateTime<Utc> = Utc::now();
+    if settings.test_destination {
+        test_destination_command(&settings)?;
 
-                let start_time: u64 = now
-                    .checked_sub_signed(duration)
-                    .unwrap()
-                    .timestamp()
-                    .to_string()
-                    .parse::<u64>()?
-                    * 1_000_000;
+        return Ok(None);
+    }
 
-                journal.seek(JournalSeek::ClockRealtime { usec: start_time })?;
-            } else {
-                journal.seek(JournalSeek::Tail)?;
+    if let Some(command) = &settings.cursor_command {
+        cursor_command(&settings, command)?;
+
+        return Ok(None);
+    }
+
+    // An export is read from its start, there is no journal to seek in
+    if !settings.journal_export.is_empty() {
+        return Ok(Some((local_cursor_state, settings)));
+    }
+
+    for namespace in get_namespaces(&settings)? {
+        let mut journal = open_journal(&settings, &namespace)?;
+        let position = seek_history(&settings, &mut journal)?;
+        if verbose > 1 {
+            eprintln!(" ++ Calculated Cursor: {} [{}]", position, namespace);
+        }
+        local_cursor_state.update(CursorRecord {
+            position,
+            namespace,
+        });
+    }
+    Ok(Some((local_cursor_state, settings)))
+}
+
+// The cursor subcommands, these only touch the cursor file
+fn cursor_command(settings: &Settings, command: &str) -> Result<()> {
+    let path = &settings.last_cursor_location;
+    let namespace = settings.cursor_namespace.clone();
+    let mut cursor_state = read_cursor_state(path);
+
+    match command {
+        "show" => {
+            let statuses = cursor_state
+                .records()
+                .iter()
+                .map(|record| cursor_status(settings, record))
+                .collect::<Result<Vec<CursorStatus>>>()?;
+            println!("{}", to_yaml_string(&statuses)?);
+        }
+        "reset" => {
+            let position = reset_position(
+                settings,
+                &namespace,
+                &settings.cursor_to,
+                &settings.cursor_value,
+            )?;
+            eprintln!(" ++ Reset Cursor: {} [{}]", position, namespace);
+            cursor_state.update(CursorRecord {
+                position,
+                namespace,
+            });
+            write_cursor_state(path, &cursor_state)?;
+        }
+        "set" => {
+            let position = settings.cursor_value.clone();
+            let mut journal = open_journal(settings, &namespace)?;
+            seek_journal(
+                &mut journal,
+                JournalSeek::Cursor {
+                    cursor: position.clone(),
+                },
+            )
+            .unwrap_or_default();
+            if !journal.test_cursor(position.as_str()).unwrap_or(false) {
+                eprintln!(
+                    " !! Cursor is not in the journal, cursor-recovery will apply: {}",
+                    position
+                );
             }
+            cursor_state.update(CursorRecord {
+                position,
+                namespace,
+            });
+            write_cursor_state(path, &cursor_state)?;
         }
-        "absolute" => {
-            let absolute = config
-                .get_str("history-absolute")?
-                .as_str()
-                .parse::<DateTime<Utc>>()?
-                .timestamp()
-                .to_string()
-                .parse::<u64>()?
-                * 1_000_000;
-
-            if verbose > 1 {
-                eprintln!(" .. Seek Absolute: {:?}", absolute);
+        "export" => {
+            println!("{}", to_yaml_string(&cursor_state)?);
+        }
+        "import" => {
+            let source = &settings.cursor_value;
+            let mut yaml_string = String::default();
+            if source.is_empty() || source == "-" {
+                std::io::stdin().read_to_string(&mut yaml_string)?;
+            } else {
+                yaml_string = std::fs::read_to_string(source)?;
             }
+            let imported: CursorState = yaml_from_str(&yaml_string)?;
+            write_cursor_state(path, &imported)?;
+        }
+        command => failure::bail!("{} is not a valid cursor command!", command),
+    }
+
+    Ok(())
+}
 
-            journal.seek(JournalSeek::ClockRealtime { usec: absolute })?;
+// Our own records are always dropped first, foreground mode then echoes
+// whatever is left at the higher verbosities
+fn transforms_from_settings(settings: &Settings, forked: bool) -> Vec<Box<dyn Transform>> {
+    let mut transforms: Vec<Box<dyn Transform>> =
+        vec![Box::new(ExcludeRecord::from_settings(settings, forked))];
+
+    if settings.run_mode == Some(RunMode::Foreground) {
+        match settings.verbose {
+            4..=6 => transforms.push(Box::new(PrintRecord { pretty: false })),
+            7..=9 => transforms.push(Box::new(PrintRecord { pretty: true })),
+            _ => (),
         }
-        "count" => {
-            let count: i64 = config.get_int("history-count")?;
+    }
 
-            if verbose > 1 {
-                eprintln!(" .. Seek Records: {:?}", count);
-            }
+    transforms
+}
 
-            if count > 0 {
-                journal.seek(JournalSeek::Head)?;
-                // Make sure we are at the begining
-                loop {
-                    if let Ok(None) = journal.previous_record() {
-                        break;
-                    }
-                }
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+struct OneshotSummary {
+    until: String,
+    sent: u64,
+    acknowledged: u64,
+    namespaces: BTreeMap<String, u64>,
+}
 
-                for _ in 0..count {
-                    if let Ok(None) = journal.next_record() {
-                        break;
-                    }
-                }
+// Forward everything between the history start and history-until, or all of
+// journal-export, then exit. The cursor file is never written. A dry run
+// starts from the saved cursor, as the next real run would, and prints
+// instead of sending.
+fn oneshot_wrapper(mut cursor_state: CursorState, settings: Settings) -> Result<()> {
+    let verbose = settings.verbose;
+    let dry_run = settings.run_mode == Some(RunMode::DryRun);
+    let until_time = get_history_until(&settings)?.unwrap_or_else(Utc::now);
+    let until =
+        until_time.timestamp() as u64 * 1_000_000 + u64::from(until_time.timestamp_subsec_micros());
+    let timeout = settings.oneshot_timeout;
+    let (event_sender, event_receiver) = mpsc::sync_channel::<Event>(300);
+    let (cursor_value_sender, cursor_value_receiver) = mpsc::sync_channel::<CursorRecord>(300);
+    let mut summary = OneshotSummary {
+        until: until_time.to_rfc3339().replace("+00:00", "Z"),
+        ..OneshotSummary::default()
+    };
 
-                (0..count).for_each(|_| {
-                    journal.next_record().unwrap();
-                });
-            } else if count < 0 {
-                journal.seek(JournalSeek::Tail)?;
-                // Tail does not always go to the end
-                loop {
-                    if let Ok(None) = journal.next_record() {
-                        break;
-                    }
-                }
+    let mut sink = sink_from_settings(&settings);
 
-                for _ in count..0 {
-                    if let Ok(None) = journal.previous_record() {
-                        break;
-                    }
-                }
+    if dry_run {
+        for file_record in read_cursor_state(&settings.last_cursor_location).records() {
+            cursor_state.update(file_record);
+        }
+        sink = Box::new(DryRunSink::new(sink));
+    }
+    thread::spawn(move || run_sink(sink.as_mut(), &event_receiver, &cursor_value_sender));
+
+    let namespaces = get_namespaces(&settings)?;
+    let (done_sender, done_receiver) = mpsc::channel::<(String, Result<u64>)>();
+    for namespace in namespaces.iter().cloned() {
+        let position = cursor_state.get(&namespace).to_string();
+        let settings = settings.clone();
+        let event_sender = event_sender.clone();
+        let done_sender = done_sender.clone();
+        thread::spawn(move || {
+            let mut transforms = transforms_from_settings(&settings, false);
+            let result = if settings.journal_export.is_empty() {
+                follow_journal(
+                    &settings,
+                    &namespace,
+                    &position,
+                    Some(until),
+                    &mut transforms,
+                    &event_sender,
+                )
             } else {
-                journal.seek(JournalSeek::Tail)?;
-                // Tail does not always go to the end
-                loop {
-                    if let Ok(None) = journal.next_record() {
-                        break;
-                    }
+                forward_export(&settings, &mut transforms, &event_sender)
+            };
+            done_sender.send((namespace, result)).unwrap_or_default();
+        });
+    }
+
+    // Keep draining acknowledgements so the sender never blocks
+    let mut finished = 0;
+    let mut failed = false;
+    let mut last_progress = StdInstant::now();
+    while finished < namespaces.len() || summary.acknowledged < summary.sent {
+        while let Ok((namespace, result)) = done_receiver.try_recv() {
+            finished += 1;
+            match result {
+                Ok(count) => {
+                    summary.sent += count;
+                    summary.namespaces.insert(namespace, count);
                 }
+                Err(error) => {
+                    eprintln!("{:#?}\nwhile reading namespace: [{}]", error, namespace);
+                    failed = true;
+                }
+            }
+        }
+        match cursor_value_receiver.recv_timeout(StdDuration::from_millis(250)) {
+            Ok(_) => {
+                summary.acknowledged += 1;
+                last_progress = StdInstant::now();
+                // is_multiple_of needs a newer compiler than this builds with
+                #[allow(clippy::manual_is_multiple_of)]
+                if verbose >= 3 && summary.acknowledged % 10_000 == 0 {
+                    eprintln!(" <> Acknowledged {}", summary.acknowledged);
+                }
+            }
+            Err(_) if last_progress.elapsed() > timeout => {
+                eprint!("{}", to_yaml_string(&summary)?);
+                failure::bail!(
+                    "No progress for {:?}, {} of {} records acknowledged",
+                    timeout,
+                    summary.acknowledged,
+                    summary.sent
+                );
             }
+            Err(_) => (),
         }
-        history_type => panic!("{} is not a valid history-type!", history_type),
     }
-    local_cursor_value.position = journal.cursor()?;
-    if verbose > 1 {
-        eprintln!(" ++ Calculated Cursor: {}", local_cursor_value.position);
+
+    // The wire bytes of a dry run own stdout
+    if dry_run {
+        eprintln!("{}", to_yaml_string(&summary)?);
+    } else {
+        println!("{}", to_yaml_string(&summary)?);
+    }
+
+    if failed {
+        failure::bail!("Not every namespace could be read completely");
     }
-    Ok((local_cursor_value, config))
+
+    Ok(())
 }
 
 fn main_wrapper() -> Result<()> {
-    let (init_cursor, config) = initialize_the_environment()?;
-    let mut local_cursor_value = init_cursor;
-    let verbose = config.get_int("verbose").unwrap_or(0);
-    let main_loop_count = config.get_int("main-loop-count").unwrap_or(100_000);
-    let _main_loop_time = config
-        .get_str("main-loop-time")
-        .unwrap_or_else(|_| String::from("23h"));
-    let main_loop_message = config.get_int("main-loop-message").unwrap_or(10_000);
-    let cursor_location_file = config.get_str("last-cursor-location")?;
-    let (json_value_sender, json_value_receiver) =
-        mpsc::sync_channel::<(JsonValue, CursorRecord)>(300);
+    let (init_cursor, settings) = match initialize_the_environment()? {
+        Some(initial) => initial,
+        None => return Ok(()),
+    };
+    // An export has an end, so it is read like a oneshot whatever the run mode
+    if settings.run_mode == Some(RunMode::Oneshot)
+        || settings.run_mode == Some(RunMode::DryRun)
+        || !settings.journal_export.is_empty()
+    {
+        return oneshot_wrapper(init_cursor, settings);
+    }
+    let mut local_cursor_state = init_cursor;
+    let verbose = settings.verbose;
+    let cursor_location_file = settings.last_cursor_location.clone();
+    let (event_sender, event_receiver) = mpsc::sync_channel::<Event>(300);
     let (cursor_value_sender, cursor_value_receiver) = mpsc::sync_channel::<CursorRecord>(300);
-    let mut old_mem_value = 0;
     if verbose >= 3 {
         eprintln!(" <> Start of main_wrapper ");
     }
@@ -583,161 +851,63 @@ fn main_wrapper() -> Result<()> {
                 if verbose >= 3 {
                     eprintln!(" => Start of Child");
                 }
-                let remote_host = HostRecord {
-                    host: config
-                        .get_str("host-name")
-                        .unwrap_or_else(|_| "127.0.0.1".to_string()),
-                    port: config
-                        .get_int("host-port")
-                        .unwrap_or(9000)
-                        .to_string()
-                        .parse::<u16>()
-                        .unwrap(),
-                    protocol: config
-                        .get_str("host-protocol")
-                        .unwrap_or_else(|_| "tcp".to_string()),
-                };
-
+                let mut sink = sink_from_settings(&settings);
                 thread::spawn(move || {
-                    send_json_to_remote_host(
-                        &remote_host,
-                        &json_value_receiver,
-                        &cursor_value_sender,
-                    )
+                    run_sink(sink.as_mut(), &event_receiver, &cursor_value_sender)
                 });
 
-                // Make sure the file handle goes out of scope
-                {
-                    if let Ok(mut cursor_file) = OpenOptions::new()
-                        .read(true)
-                        .open(&cursor_location_file.as_str())
-                    {
-                        let mut yaml_string = String::default();
-                        cursor_file
-                            .read_to_string(&mut yaml_string)
-                            .unwrap_or_default();
-                        let file_cursor: CursorRecord =
-                            yaml_from_str(&yaml_string).unwrap_or_default();
-                        // overwrite the cursor value if a record exists in the state file
-                        if file_cursor != CursorRecord::default() {
-                            local_cursor_value = file_cursor;
-                            if verbose >= 3 {
-                                eprintln!(" ++ Using Cursor: {}", local_cursor_value.position);
-                            }
-                        }
+                // overwrite the cursor values that exist in the state file
+                for file_record in read_cursor_state(cursor_location_file.as_str()).records() {
+                    if verbose >= 3 {
+                        eprintln!(
+                            " ++ Using Cursor: {} [{}]",
+                            file_record.position, file_record.namespace
+                        );
                     }
+                    local_cursor_state.update(file_record);
                 }
 
+                let written_cursor_state = local_cursor_state.clone();
                 thread::spawn(move || {
-                    read_write_cursor_thread(cursor_location_file.as_str(), &cursor_value_receiver)
+                    read_write_cursor_thread(
+                        cursor_location_file.as_str(),
+                        written_cursor_state,
+                        &cursor_value_receiver,
+                    )
                 });
 
-                let mut journal = Journal::open(JournalFiles::All, false, false)?;
-                journal
-                    .seek(JournalSeek::Cursor {
-                        cursor: local_cursor_value.position.clone(),
-                    })
-                    .unwrap_or_default();
-                let mut sleep_count = 0i64;
-                for loop_count in 1..main_loop_count {
-                    // need to do this because journald does not cleanup after itself
-                    if verbose >= 3 && loop_count % 1_000 == 0 {
-                        if loop_count % main_loop_message == 0 {
-                            eprintln!(" <> Loop/Sleep {}/{}", loop_count, sleep_count);
-                            eprintln!(" ++ Cursor: {}", local_cursor_value.position);
-                        }
-                        let mut stats = rusage {
-                            ru_utime: timeval {
-                                tv_sec: 0,
-                                tv_usec: 0,
-                            },
-                            ru_stime: timeval {
-                                tv_sec: 0,
-                                tv_usec: 0,
-                            },
-                            ru_maxrss: 0,
-                            ru_ixrss: 0,
-                            ru_idrss: 0,
-                            ru_isrss: 0,
-                            ru_minflt: 0,
-                            ru_majflt: 0,
-                            ru_nswap: 0,
-                            ru_inblock: 0,
-                            ru_oublock: 0,
-                            ru_msgsnd: 0,
-                            ru_msgrcv: 0,
-                            ru_nsignals: 0,
-                            ru_nvcsw: 0,
-                            ru_nivcsw: 0,
-                        };
-                        let stats_ptr: *mut rusage = &mut stats;
-                        let usage_result: c_int;
-                        unsafe {
-                            usage_result = getrusage(RUSAGE_SELF, stats_ptr);
-                        }
-                        if usage_result == 0 && old_mem_value != stats.ru_maxrss {
-                            eprintln!(" -- Max RSS {}", stats.ru_maxrss);
-                            old_mem_value = stats.ru_maxrss;
-                        }
-                    }
-                    let candidate = journal.next_record()?;
-                    let record = match candidate {
-                        Some(matched_record) => matched_record,
-                        None => loop {
-                            if let Some(matched_record) = journal.await_next_record(None)? {
-                                sleep_count += 1;
-                                break matched_record;
-                            }
-                        },
-                    };
-
-                    local_cursor_value = CursorRecord {
-                        position: journal.cursor().unwrap_or_default(),
-                    };
-                    if local_cursor_value != CursorRecord::default() {
-                        let timestamp: DateTime<Utc> = journal
-                            .timestamp()
-                            .unwrap_or_else(|_| Utc::now().into())
-                            .into();
-                        let timestamp_str = timestamp.to_rfc3339().replace("+00:00", "Z");
-                        let mut json_map = JsonMap::new();
-                        json_map.insert("@timestamp".into(), timestamp_str.clone().into());
-                        json_map.insert("journald.timestamp".into(), timestamp_str.into());
-                        json_map.insert(
-                            "journald.cursor".into(),
-                            local_cursor_value.position.clone().into(),
-                        );
-                        record.into_iter().for_each(|(record_key, record_value)| {
-                            json_map.insert(
-                                record_key
-                                    .to_lowercase()
-                                    .replace("_", "-")
-                                    .trim_start_matches('-')
-                                    .replace("source", "originator"),
-                                record_value.as_str().into(),
-                            );
-                        });
-                        let json_value: JsonValue = json_map.into();
-                        json_value_sender
-                            .send((json_value.clone(), local_cursor_value.clone()))
-                            .unwrap_or_default();
-                        if config.get_str("run-mode").unwrap_or_else(|_| "".into()) == "foreground"
-                        {
-                            match verbose {
-                                4 | 5 | 6 => {
-                                    let json_string = serde_json::to_string(&json_value)?;
-                                    println!("{}", json_string);
-                                }
-                                7 | 8 | 9 => {
-                                    let json_string_pretty =
-                                        serde_json::to_string_pretty(&json_value)?;
-                                    println!("{}", json_string_pretty);
-                                }
-                                _ => (),
-                            }
+                if verbose >= 3 {
+                    eprintln!(
+                        " ++ Excluding: {:?}",
+                        ExcludeRecord::from_settings(&settings, true)
+                    );
+                }
+
+                // Every namespace is followed by its own reader, the child
+                // is recycled as soon as any one of them is done
+                let (done_sender, done_receiver) = mpsc::channel::<String>();
+                for namespace in get_namespaces(&settings)? {
+                    let position = local_cursor_state.get(&namespace).to_string();
+                    let settings = settings.clone();
+                    let event_sender = event_sender.clone();
+                    let done_sender = done_sender.clone();
+                    thread::spawn(move || {
+                        let mut transforms = transforms_from_settings(&settings, true);
+                        if let Err(error) = follow_journal(
+                            &settings,
+                            &namespace,
+                            &position,
+                            None,
+                            &mut transforms,
+                            &event_sender,
+                        ) {
+                            eprintln!("{:#?}\nwhile following namespace: [{}]", error, namespace);
                         }
-                    }
+                        done_sender.send(namespace).unwrap_or_default();
+                    });
                 }
+                done_receiver.recv().unwrap_or_default();
+
                 if verbose >= 3 {
                     eprintln!(" => Exiting Child");
                 }
@@ -789,7 +959,10 @@ fn main_wrapper() -> Result<()> {
     Ok(())
 }
 
-// Purely here to catch an error and panic
+// Print any error of main_wrapper and exit with a failure status
 fn main() {
-    main_wrapper().unwrap()
+    if let Err(error) = main_wrapper() {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,116 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use std::{collections::BTreeMap, fs::File, io::BufReader};
+
+use crate::{
+    cursor::{cursor_field, CursorRecord},
+    export::{export_event, export_realtime, read_export_entry},
+    pipeline::{Event, Source},
+    Result,
+};
+
+// A Source over records held in memory, such as those of a Journal Export
+// Format fixture, so that the pipeline can run without a journal. The cursors
+// are synthetic, i= is the record counting from one and t= its realtime, and
+// look enough like real ones for cursor_field and cursor-recovery.
+pub struct MemorySource {
+    namespace: String,
+    records: Vec<BTreeMap<String, String>>,
+    next: usize,
+}
+
+impl MemorySource {
+    pub fn new(namespace: &str, records: Vec<BTreeMap<String, String>>) -> MemorySource {
+        MemorySource {
+            namespace: namespace.to_string(),
+            records,
+            next: 0,
+        }
+    }
+
+    pub fn from_export_file(namespace: &str, path: &str) -> Result<MemorySource> {
+        let mut reader = BufReader::new(File::open(path).map_err(|error| {
+            failure::format_err!("{:#?}\nwhile trying to open file: {}", error, path)
+        })?);
+        let mut records = vec![];
+        while let Some(entry) = read_export_entry(&mut reader)? {
+            records.push(entry);
+        }
+
+        Ok(MemorySource::new(namespace, records))
+    }
+
+    // Continue after the record of a saved cursor, as the follower would
+    pub fn after(mut self, position: &str) -> MemorySource {
+        self.next = cursor_field(position, "i")
+            .and_then(|index| usize::from_str_radix(&index, 16).ok())
+            .unwrap_or(0);
+        self
+    }
+
+    pub fn cursor(index: usize, realtime: u64) -> String {
+        format!("s=memory;i={:x};t={:x}", index, realtime)
+    }
+}
+
+impl Source for MemorySource {
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        let record = match self.records.get(self.next) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        self.next += 1;
+
+        let realtime = export_realtime(record).unwrap_or_default();
+        let cursor = CursorRecord {
+            position: MemorySource::cursor(self.next, realtime),
+            namespace: self.namespace.clone(),
+        };
+
+        Ok(Some(export_event(record, cursor)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message: &str) -> BTreeMap<String, String> {
+        let mut record = BTreeMap::new();
+        record.insert(String::from("MESSAGE"), message.to_string());
+        record.insert(String::from("__REALTIME_TIMESTAMP"), String::from("16"));
+        record
+    }
+
+    #[test]
+    fn synthetic_cursors_resume_after_the_record() {
+        let records = vec![record("one"), record("two"), record("three")];
+        let mut source = MemorySource::new("", records.clone());
+        let first = source.next_event().unwrap().unwrap();
+        assert_eq!(first.cursor.position, "s=memory;i=1;t=10");
+        assert_eq!(first.value["message"], "one");
+        assert_eq!(first.value["@timestamp"], "1970-01-01T00:00:00.000016Z");
+        assert!(first.value.get("--realtime-timestamp").is_none());
+
+        let mut resumed = MemorySource::new("", records).after(&first.cursor.position);
+        let second = resumed.next_event().unwrap().unwrap();
+        assert_eq!(second.value["message"], "two");
+        assert_eq!(
+            resumed.next_event().unwrap().unwrap().value["message"],
+            "three"
+        );
+        assert_eq!(resumed.next_event().unwrap(), None);
+    }
+}
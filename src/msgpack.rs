@@ -0,0 +1,155 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// MessagePack for the Fluentd Forward protocol, written with rmp and read
+// back as rmpv values. What a server sends is read with limits, so that a
+// broken or hostile server cannot have us allocate or recurse without end.
+
+use rmp::encode::{
+    write_array_len, write_bool, write_ext_meta, write_f64, write_map_len, write_nil, write_sint,
+    write_str, write_uint,
+};
+
+pub use rmpv::Value as MsgValue;
+
+use serde_json::Value as JsonValue;
+
+use std::{convert::TryFrom, io::Read};
+
+use crate::Result;
+
+// The most bytes one value read from a server may take, HELO, PONG and the
+// acks are all far smaller
+pub const MAX_VALUE_BYTES: u64 = 64 * 1024;
+
+// How deep a value read from a server may go, rmpv counts both a container
+// and each value in it
+pub const MAX_DEPTH: usize = 32;
+
+fn length(length: usize) -> Result<u32> {
+    u32::try_from(length)
+        .map_err(|_| failure::format_err!("{} items are too many for MessagePack", length))
+}
+
+// The Fluentd EventTime, extension type 0 of seconds and nanoseconds
+pub fn write_event_time(out: &mut Vec<u8>, seconds: u32, nanoseconds: u32) -> Result<()> {
+    write_ext_meta(out, 8, 0)?;
+    out.extend_from_slice(&seconds.to_be_bytes());
+    out.extend_from_slice(&nanoseconds.to_be_bytes());
+    Ok(())
+}
+
+pub fn write_json(out: &mut Vec<u8>, value: &JsonValue) -> Result<()> {
+    match value {
+        JsonValue::Null => write_nil(out)?,
+        JsonValue::Bool(value) => write_bool(out, *value)?,
+        JsonValue::Number(number) => {
+            if let Some(value) = number.as_u64() {
+                write_uint(out, value)?;
+            } else if let Some(value) = number.as_i64() {
+                write_sint(out, value)?;
+            } else {
+                write_f64(out, number.as_f64().unwrap_or_default())?;
+            }
+        }
+        JsonValue::String(text) => write_str(out, text)?,
+        JsonValue::Array(items) => {
+            write_array_len(out, length(items.len())?)?;
+            for item in items {
+                write_json(out, item)?;
+            }
+        }
+        JsonValue::Object(object) => {
+            write_map_len(out, length(object.len())?)?;
+            for (key, value) in object.iter() {
+                write_str(out, key)?;
+                write_json(out, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Read one value, blocking until all of it has arrived
+pub fn read_value<R: Read>(reader: &mut R) -> Result<MsgValue> {
+    let mut limited = reader.take(MAX_VALUE_BYTES);
+    let value = rmpv::decode::read_value_with_max_depth(&mut limited, MAX_DEPTH);
+    if value.is_err() && limited.limit() == 0 {
+        failure::bail!(
+            "The server sent a MessagePack value of more than {} bytes",
+            MAX_VALUE_BYTES
+        );
+    }
+    Ok(value?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rmp::encode::write_bin;
+
+    #[test]
+    fn written_values_read_back_the_same() {
+        let long = "x".repeat(300);
+        let mut out = vec![];
+        write_json(
+            &mut out,
+            &serde_json::json!({ "message": long, "pid": 42, "offset": -3000, "ok": true, "load": 0.5, "tags": [null] }),
+        )
+        .unwrap();
+        write_bin(&mut out, b"nonce").unwrap();
+        write_event_time(&mut out, 1_792_326_635, 1_000).unwrap();
+
+        let mut reader = std::io::Cursor::new(out);
+        let record = read_value(&mut reader).unwrap();
+        assert_eq!(record["message"].as_str(), Some(long.as_str()));
+        assert_eq!(record["pid"].as_u64(), Some(42));
+        assert_eq!(record["offset"].as_i64(), Some(-3000));
+        assert_eq!(record["ok"], MsgValue::Boolean(true));
+        assert_eq!(record["load"].as_f64(), Some(0.5));
+        assert_eq!(record["tags"], MsgValue::Array(vec![MsgValue::Nil]));
+        assert_eq!(
+            read_value(&mut reader).unwrap().as_slice(),
+            Some(&b"nonce"[..])
+        );
+        assert_eq!(
+            read_value(&mut reader).unwrap(),
+            MsgValue::Ext(0, vec![0x6a, 0xd4, 0xbb, 0xeb, 0, 0, 0x03, 0xe8])
+        );
+    }
+
+    #[test]
+    fn values_from_a_server_are_limited() {
+        // A string that claims 4 GiB, with only a little of it sent
+        let mut huge = vec![0xdb, 0xff, 0xff, 0xff, 0xff];
+        huge.extend(vec![b'x'; MAX_VALUE_BYTES as usize]);
+        let error = read_value(&mut std::io::Cursor::new(huge)).unwrap_err();
+        assert!(
+            error.to_string().contains("more than 65536 bytes"),
+            "{}",
+            error
+        );
+
+        let mut nested = vec![0x91; MAX_DEPTH + 1];
+        nested.push(0xc0);
+        assert!(read_value(&mut std::io::Cursor::new(nested)).is_err());
+        let mut nested = vec![0x91; 8];
+        nested.push(0xc0);
+        assert!(read_value(&mut std::io::Cursor::new(nested)).is_ok());
+
+        // Truncated input is an error, not a panic
+        assert!(read_value(&mut std::io::Cursor::new(vec![0xdc, 0xff])).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use chrono::{DateTime, Utc};
+
+use serde_json::Value as JsonValue;
+
+use std::{
+    collections::BTreeMap,
+    sync::mpsc,
+    thread,
+    time::{Duration as StdDuration, Instant as StdInstant},
+};
+
+use crate::{
+    cursor::CursorRecord,
+    export::{export_realtime, usec_to_datetime},
+    http::HttpClient,
+    settings::Settings,
+    sink::RemoteConnection,
+    Result,
+};
+
+// A record on its way through the pipeline, together with the cursor to save
+// once a sink has acknowledged it. An empty position leaves the saved cursor
+// alone, such as for a synthetic record. The fields are the record as read,
+// including __CURSOR and the timestamps, for sinks that need the journal's
+// own names; a synthetic record has none.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Event {
+    pub value: JsonValue,
+    pub cursor: CursorRecord,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Event {
+    // When the record was written, to the microsecond for a journal record
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        match export_realtime(&self.fields) {
+            Some(usec) => Some(usec_to_datetime(usec)),
+            None => self
+                .value
+                .get("@timestamp")
+                .and_then(JsonValue::as_str)
+                .and_then(|timestamp| timestamp.parse::<DateTime<Utc>>().ok()),
+        }
+    }
+}
+
+// Yields records in order, None means the source is done
+pub trait Source {
+    fn next_event(&mut self) -> Result<Option<Event>>;
+}
+
+// Changes or drops a record between the source and the sink
+pub trait Transform: Send {
+    fn transform(&mut self, event: Event) -> Option<Event>;
+}
+
+// Delivers batches of records, returning the cursors that may be saved. An
+// error leaves the whole batch to be sent again.
+pub trait Sink: Send {
+    // Such as "filebeat tcp://127.0.0.1:9000"
+    fn label(&self) -> String;
+
+    // Exactly what goes on the wire for a batch
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>>;
+
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>>;
+
+    // The connection used, if any, for test-destination to report on
+    fn connection(&mut self) -> Option<&mut RemoteConnection> {
+        None
+    }
+
+    // The HTTP client used, if any, whose host test-destination connects to
+    // ahead of the request to report on
+    fn http_client(&self) -> Option<&HttpClient> {
+        None
+    }
+
+    // What the destination made of the last batch, for test-destination
+    fn acknowledgement(&self) -> String {
+        String::from("not supported")
+    }
+
+    // The most records to send at once
+    fn max_batch(&self) -> usize {
+        MAX_BATCH
+    }
+
+    // How long to wait for more records before sending a batch that is not full
+    fn linger(&self) -> StdDuration {
+        StdDuration::from_secs(0)
+    }
+
+    // How long to wait after a failure, such as a Retry-After of the destination
+    fn retry_delay(&self) -> StdDuration {
+        RETRY_DELAY
+    }
+}
+
+// The most records handed to a sink at once, unless it asks for another size
+pub const MAX_BATCH: usize = 100;
+
+// How long to wait before sending a failed batch again
+pub const RETRY_DELAY: StdDuration = StdDuration::from_millis(1235);
+
+pub fn apply_transforms(transforms: &mut [Box<dyn Transform>], event: Event) -> Option<Event> {
+    transforms
+        .iter_mut()
+        .try_fold(event, |event, transform| transform.transform(event))
+}
+
+// Read the source until it is done, returning the number of records handed
+// to the sender
+pub fn forward(
+    source: &mut dyn Source,
+    transforms: &mut [Box<dyn Transform>],
+    sender: &mpsc::SyncSender<Event>,
+) -> Result<u64> {
+    let mut sent_count = 0u64;
+
+    loop {
+        let event = match source.next_event()? {
+            Some(event) => event,
+            None => break,
+        };
+        if let Some(event) = apply_transforms(transforms, event) {
+            sender.send(event).unwrap_or_default();
+            sent_count += 1;
+        }
+    }
+
+    Ok(sent_count)
+}
+
+// Hand everything received to the sink in batches and pass the acknowledged
+// cursors on. A batch is retried until it is accepted, returns once every
+// sender is gone.
+pub fn run_sink(
+    sink: &mut dyn Sink,
+    receiver: &mpsc::Receiver<Event>,
+    cursor_sender: &mpsc::SyncSender<CursorRecord>,
+) {
+    let max_batch = sink.max_batch();
+    let linger = sink.linger();
+
+    while let Ok(first) = receiver.recv() {
+        let mut batch = vec![first];
+        let deadline = StdInstant::now() + linger;
+        while batch.len() < max_batch {
+            let wait = deadline.saturating_duration_since(StdInstant::now());
+            let received = if wait == StdDuration::from_secs(0) {
+                receiver.try_recv().ok()
+            } else {
+                receiver.recv_timeout(wait).ok()
+            };
+            match received {
+                Some(event) => batch.push(event),
+                None => break,
+            }
+        }
+
+        // Only the first failure of a batch is reported, not every retry
+        let mut failed_since: Option<StdInstant> = None;
+        loop {
+            match sink.send(&batch) {
+                Ok(cursors) => {
+                    if let Some(since) = failed_since {
+                        eprintln!(
+                            " ++ Sending to {} again after {:?}",
+                            sink.label(),
+                            since.elapsed()
+                        );
+                    }
+                    for cursor in cursors {
+                        cursor_sender.send(cursor).unwrap_or_default();
+                    }
+                    break;
+                }
+                Err(error) => {
+                    if failed_since.is_none() {
+                        eprintln!(" !! {}\nwhile sending to: {}", error, sink.label());
+                        failed_since = Some(StdInstant::now());
+                    }
+                    thread::sleep(sink.retry_delay());
+                }
+            }
+        }
+    }
+}
+
+// Journal matches can only select records, never reject them, so our own
+// records and those of our relay are dropped here instead
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ExcludeRecord {
+    pub pids: Vec<String>,
+    pub comms: Vec<String>,
+    pub units: Vec<String>,
+}
+
+impl ExcludeRecord {
+    // Build the exclusion list from our own process plus the configured units.
+    // Only a forked child has a parent of our own, otherwise the parent is the
+    // shell or systemd and its records are wanted.
+    pub fn from_settings(settings: &Settings, forked: bool) -> ExcludeRecord {
+        let mut exclude = ExcludeRecord::default();
+
+        if settings.exclude_self {
+            exclude.pids.push(std::process::id().to_string());
+            if forked {
+                exclude.pids.push(nix::unistd::getppid().to_string());
+            }
+            if let Ok(comm) = std::fs::read_to_string("/proc/self/comm") {
+                exclude.comms.push(comm.trim().to_string());
+            }
+            if let Ok(cgroup) = std::fs::read_to_string("/proc/self/cgroup") {
+                // Lines look like "0::/system.slice/journaldeliver.service"
+                cgroup
+                    .lines()
+                    .filter_map(|line| line.rsplit(':').next())
+                    .flat_map(|path| path.split('/'))
+                    .filter(|part| part.ends_with(".service"))
+                    .for_each(|unit| exclude.units.push(unit.to_string()));
+            }
+        }
+
+        exclude.units.extend(settings.exclude_units.iter().cloned());
+
+        exclude.pids.dedup();
+        exclude.units.sort();
+        exclude.units.dedup();
+
+        exclude
+    }
+
+    // Checked against the trusted fields that journald adds itself, never the
+    // JSON, where a PID= or COMM= field of the sender could stand in for them
+    pub fn matches(&self, record: &BTreeMap<String, String>) -> bool {
+        let field_in = |field: &str, values: &[String]| {
+            record
+                .get(field)
+                .map(|value| values.iter().any(|candidate| candidate == value))
+                .unwrap_or(false)
+        };
+
+        field_in("_PID", &self.pids)
+            || field_in("_COMM", &self.comms)
+            || field_in("_SYSTEMD_UNIT", &self.units)
+    }
+}
+
+impl Transform for ExcludeRecord {
+    fn transform(&mut self, event: Event) -> Option<Event> {
+        if self.matches(&event.fields) {
+            None
+        } else {
+            Some(event)
+        }
+    }
+}
+
+// Echo every record to stdout in foreground mode, one line each or pretty
+pub struct PrintRecord {
+    pub pretty: bool,
+}
+
+impl Transform for PrintRecord {
+    fn transform(&mut self, event: Event) -> Option<Event> {
+        let printed = if self.pretty {
+            serde_json::to_string_pretty(&event.value)
+        } else {
+            serde_json::to_string(&event.value)
+        };
+        if let Ok(json_string) = printed {
+            println!("{}", json_string);
+        }
+        Some(event)
+    }
+}
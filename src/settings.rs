@@ -0,0 +1,1325 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use config::{Config, File as ConfigFile, FileFormat, Value as ConfigValue};
+
+use parse_duration::parse as parse_duration;
+
+use serde::{
+    de::{Deserializer, Error as DeError},
+    Deserialize,
+};
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use serde_yaml::{to_string as to_yaml_string, Value as YamlValue};
+
+use toml::Value as TomlValue;
+
+use std::{
+    collections::BTreeMap, iter::FromIterator, path::Path, result::Result as StdResult,
+    time::Duration as StdDuration,
+};
+
+use crate::{
+    elasticsearch::index_name, http::parse_http_url, http_sink::parse_header, loki::parse_label,
+    Result,
+};
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunMode {
+    Daemon,
+    Foreground,
+    Oneshot,
+    DryRun,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryType {
+    Duration,
+    Absolute,
+    Count,
+    Boot,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum CursorRecovery {
+    Head,
+    Tail,
+    Timestamp,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalSource {
+    All,
+    System,
+    User,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostType {
+    #[default]
+    Filebeat,
+    JournalRemote,
+    Gelf,
+    FluentForward,
+    Elasticsearch,
+    Loki,
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostProtocol {
+    #[default]
+    Tcp,
+    Udp,
+    // TCP with TLS, https for the host types that speak HTTP
+    Tls,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum HttpBodyFormat {
+    Ndjson,
+    JsonArray,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum LokiEncoding {
+    Protobuf,
+    Json,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum LokiLineFormat {
+    Json,
+    Logfmt,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum GelfCompression {
+    Gzip,
+    Zlib,
+    #[serde(rename = "none")]
+    Uncompressed,
+}
+
+// The merged config, deserialized once it has been checked. The defaults
+// match configs/defaults.yaml, the cursor and run-mode keys only ever come
+// from the command line.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct Settings {
+    pub configs: Vec<String>,
+    pub verbose: i64,
+    pub last_cursor_location: String,
+    pub cursor_recovery: CursorRecovery,
+    pub print_config: bool,
+    pub print_config_format: String,
+    pub list_config_files: bool,
+    pub check_config: bool,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub history_duration: StdDuration,
+    pub history_absolute: DateTime<Utc>,
+    pub history_count: i64,
+    pub history_boot: String,
+    pub history_type: HistoryType,
+    pub history_until: String,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub oneshot_timeout: StdDuration,
+    pub journal_source: JournalSource,
+    pub journal_runtime_only: bool,
+    pub journal_local_only: bool,
+    pub journal_directory: String,
+    pub journal_files: Vec<String>,
+    pub journal_export: String,
+    pub namespaces: Vec<String>,
+    pub host_name: String,
+    pub host_port: u16,
+    pub host_type: HostType,
+    pub host_protocol: HostProtocol,
+    pub tls_ca_file: String,
+    pub tls_cert_file: String,
+    pub tls_key_file: String,
+    pub gelf_compression: GelfCompression,
+    pub gelf_chunk_size: usize,
+    pub fluent_tag: String,
+    pub fluent_require_ack: bool,
+    pub fluent_shared_key: String,
+    pub elasticsearch_index: String,
+    pub elasticsearch_pipeline: String,
+    pub elasticsearch_username: String,
+    pub elasticsearch_password: String,
+    pub elasticsearch_api_key: String,
+    pub elasticsearch_dead_letter: String,
+    pub loki_labels: Vec<String>,
+    pub loki_line_format: LokiLineFormat,
+    pub loki_encoding: LokiEncoding,
+    pub loki_tenant: String,
+    pub loki_dead_letter: String,
+    pub http_url: String,
+    pub http_method: String,
+    pub http_headers: Vec<String>,
+    pub http_bearer_token_file: String,
+    pub http_body_format: HttpBodyFormat,
+    pub http_batch_size: usize,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub http_linger: StdDuration,
+    pub http_gzip: bool,
+    pub http_dead_letter: String,
+    pub main_loop_count: i64,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub main_loop_time: StdDuration,
+    pub main_loop_message: i64,
+    pub exclude_self: bool,
+    pub exclude_units: Vec<String>,
+    pub run_mode: Option<RunMode>,
+    pub test_destination: bool,
+    pub cursor_command: Option<String>,
+    pub cursor_namespace: String,
+    pub cursor_to: String,
+    pub cursor_value: String,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            configs: vec![
+                String::from("/usr/share/journaldeliver/default.yaml"),
+                String::from("/var/lib/journaldeliver/default.yaml"),
+                String::from("/etc/journaldeliver/default.yaml"),
+                String::from("/etc/journaldeliver/conf.d/*.yaml"),
+            ],
+            verbose: 1,
+            last_cursor_location: String::from("/var/lib/journaldeliver/cursor-location.yaml"),
+            cursor_recovery: CursorRecovery::Timestamp,
+            print_config: false,
+            print_config_format: String::from("yaml"),
+            list_config_files: false,
+            check_config: false,
+            history_duration: StdDuration::from_secs(0),
+            history_absolute: Utc.ymd(2018, 1, 1).and_hms(0, 0, 0),
+            history_count: -3,
+            history_boot: String::from("0"),
+            history_type: HistoryType::Count,
+            history_until: String::default(),
+            oneshot_timeout: StdDuration::from_secs(60),
+            journal_source: JournalSource::All,
+            journal_runtime_only: false,
+            journal_local_only: false,
+            journal_directory: String::default(),
+            journal_files: vec![],
+            journal_export: String::default(),
+            namespaces: vec![],
+            host_name: String::from("127.0.0.1"),
+            host_port: 9000,
+            host_type: HostType::Filebeat,
+            host_protocol: HostProtocol::Tcp,
+            tls_ca_file: String::default(),
+            tls_cert_file: String::default(),
+            tls_key_file: String::default(),
+            gelf_compression: GelfCompression::Gzip,
+            gelf_chunk_size: 1420,
+            fluent_tag: String::from("journal.{_SYSTEMD_UNIT}"),
+            fluent_require_ack: true,
+            fluent_shared_key: String::default(),
+            elasticsearch_index: String::from("journal-%Y.%m.%d"),
+            elasticsearch_pipeline: String::default(),
+            elasticsearch_username: String::default(),
+            elasticsearch_password: String::default(),
+            elasticsearch_api_key: String::default(),
+            elasticsearch_dead_letter: String::from(
+                "/var/lib/journaldeliver/elasticsearch-dead-letter.ndjson",
+            ),
+            loki_labels: vec![
+                String::from("unit=systemd-unit"),
+                String::from("host=hostname"),
+                String::from("priority=priority"),
+            ],
+            loki_line_format: LokiLineFormat::Json,
+            loki_encoding: LokiEncoding::Protobuf,
+            loki_tenant: String::default(),
+            loki_dead_letter: String::from("/var/lib/journaldeliver/loki-dead-letter.ndjson"),
+            http_url: String::default(),
+            http_method: String::from("POST"),
+            http_headers: vec![],
+            http_bearer_token_file: String::default(),
+            http_body_format: HttpBodyFormat::Ndjson,
+            http_batch_size: 100,
+            http_linger: StdDuration::from_secs(0),
+            http_gzip: false,
+            http_dead_letter: String::default(),
+            main_loop_count: 100_000,
+            main_loop_time: StdDuration::from_secs(23 * 60 * 60),
+            main_loop_message: 10_000,
+            exclude_self: true,
+            exclude_units: vec![],
+            run_mode: None,
+            test_destination: false,
+            cursor_command: None,
+            cursor_namespace: String::default(),
+            cursor_to: String::default(),
+            cursor_value: String::default(),
+        }
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> StdResult<StdDuration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text).map_err(DeError::custom)
+}
+
+// Origins of the config layers that are not files
+pub const DEFAULTS_ORIGIN: &str = "defaults";
+pub const ENVIRONMENT_ORIGIN: &str = "environment";
+pub const COMMAND_LINE_ORIGIN: &str = "command line";
+
+// JOURNALDELIVER_HOST_NAME sets host-name and so on
+const ENVIRONMENT_PREFIX: &str = "JOURNALDELIVER_";
+
+// Config keys set through environment variables, lists are comma separated
+fn get_environment_args<I>(variables: I) -> Result<Config>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    // An empty table rather than nothing, so it can always be merged
+    let mut config = Config::new();
+
+    for (name, value) in variables {
+        let key = match name.strip_prefix(ENVIRONMENT_PREFIX) {
+            Some(key) => key.to_lowercase().replace('_', "-"),
+            None => continue,
+        };
+        let is_list = CONFIG_SCHEMA
+            .iter()
+            .any(|&(schema_key, kind)| schema_key == key && matches!(kind, ConfigKind::List));
+
+        if is_list {
+            config.set(
+                &key,
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(ConfigValue::from)
+                    .collect::<Vec<ConfigValue>>(),
+            )?;
+        } else {
+            config.set(&key, value)?;
+        }
+    }
+
+    Ok(config)
+}
+
+// Extensions of the files picked up from a config directory
+const CONFIG_EXTENSIONS: &[&str] = &["yaml", "yml", "json", "toml"];
+
+// Match a file name against a pattern where * is any run of characters and ?
+// is any single character
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, n));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// A configs entry is a file, a directory whose config files are all used or a
+// pattern in its last component such as /etc/journaldeliver/conf.d/*.yaml.
+// Directories and patterns expand in lexical order, hidden files are skipped.
+fn expand_config_path(entry: &str) -> Vec<String> {
+    let path = Path::new(entry);
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let is_pattern = file_name.contains(['*', '?']);
+
+    let (directory, pattern) = if is_pattern {
+        (path.parent().unwrap_or_else(|| Path::new(".")), file_name)
+    } else if path.is_dir() {
+        (path, String::from("*"))
+    } else {
+        return vec![entry.to_string()];
+    };
+
+    let mut found: Vec<String> = std::fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(StdResult::ok)
+                .map(|entry| entry.path())
+                .filter(|found| found.is_file())
+                .filter(|found| {
+                    let name = found
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    let extension = found
+                        .extension()
+                        .map(|extension| extension.to_string_lossy().to_string())
+                        .unwrap_or_default();
+                    !name.starts_with('.')
+                        && wildcard_match(&pattern, &name)
+                        && (is_pattern || CONFIG_EXTENSIONS.contains(&extension.as_str()))
+                })
+                .map(|found| found.to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    found.sort();
+    found
+}
+
+// The config layers in the order they are merged, each with its origin
+pub fn get_config_layers(command_line_args: Config) -> Result<Vec<(String, Config)>> {
+    let environment_args = get_environment_args(std::env::vars())?;
+
+    // Load the default config file
+    let default_yaml_config = include_str!("../configs/defaults.yaml");
+
+    // Set to collect active config files
+    let active_paths: BTreeMap<String, isize>;
+
+    // Get the config paths that work
+    {
+        // Position in list
+        let mut pos: isize = 0;
+
+        // Create an empty config
+        let mut config = Config::default();
+
+        // Merge the default config with the environment and command line args
+        config
+            .merge(ConfigFile::from_str(default_yaml_config, FileFormat::Yaml))?
+            .merge(environment_args.clone())?
+            .merge(command_line_args.clone())?;
+
+        // Reported like check_config would, there are no layers to check yet
+        let entries = config
+            .get_array("configs")?
+            .into_iter()
+            .map(|entry| {
+                entry
+                    .into_str()
+                    .map_err(|_| failure::format_err!("configs: expected a list of strings"))
+            })
+            .collect::<Result<Vec<String>>>()?;
+
+        active_paths = entries
+            .iter()
+            .flat_map(|entry| expand_config_path(entry))
+            .map(|config_file| {
+                (config_file, {
+                    pos += 1;
+                    pos
+                })
+            })
+            .collect::<BTreeMap<String, isize>>()
+            .into_iter()
+            .filter(|(path, _)| Path::new(&path).exists())
+            .collect::<BTreeMap<String, isize>>();
+    }
+
+    let mut defaults = Config::default();
+    defaults.merge(ConfigFile::from_str(default_yaml_config, FileFormat::Yaml))?;
+
+    let mut layers = vec![(DEFAULTS_ORIGIN.to_string(), defaults)];
+
+    let mut ordered_path_list = Vec::from_iter(active_paths);
+
+    ordered_path_list.sort_by_key(|&(_, pos)| pos);
+
+    for (path, _) in ordered_path_list.into_iter() {
+        let mut file_config = Config::default();
+        file_config.merge(ConfigFile::with_name(&path))?;
+
+        layers.push((path, file_config));
+    }
+
+    layers.push((ENVIRONMENT_ORIGIN.to_string(), environment_args));
+    layers.push((COMMAND_LINE_ORIGIN.to_string(), command_line_args));
+
+    Ok(layers)
+}
+
+// Merge the config layers into the final config
+pub fn merge_config_layers(layers: Vec<(String, Config)>) -> Result<Config> {
+    let mut config = Config::default();
+
+    let mut used_path: Vec<String> = vec![];
+
+    for (origin, layer) in layers.into_iter() {
+        config.merge(layer)?;
+
+        if ![DEFAULTS_ORIGIN, ENVIRONMENT_ORIGIN, COMMAND_LINE_ORIGIN].contains(&origin.as_str()) {
+            used_path.push(origin);
+        }
+    }
+
+    config.set(
+        "configs",
+        ConfigValue::from(
+            used_path
+                .into_iter()
+                .map(ConfigValue::from)
+                .collect::<Vec<ConfigValue>>(),
+        ),
+    )?;
+
+    Ok(config)
+}
+
+// Every key of the merged config as YAML, each preceded by the layer it came from
+pub fn config_with_origins(
+    layers: &[(String, Config)],
+    config: &Config,
+    format: &str,
+) -> Result<String> {
+    let mut output = String::default();
+
+    match format {
+        "yaml" => {
+            let values = config.clone().try_into::<BTreeMap<String, YamlValue>>()?;
+            for (key, value) in values {
+                let mut entry = BTreeMap::new();
+                entry.insert(key.clone(), value);
+                output.push_str(&format!("# {}\n", config_origin(layers, &key)));
+                output.push_str(to_yaml_string(&entry)?.trim_start_matches("---\n"));
+                output.push('\n');
+            }
+        }
+        "toml" => {
+            let values = config.clone().try_into::<BTreeMap<String, TomlValue>>()?;
+            for (key, value) in values {
+                let mut entry = BTreeMap::new();
+                entry.insert(key.clone(), value);
+                output.push_str(&format!("# {}\n", config_origin(layers, &key)));
+                output.push_str(&toml::to_string(&entry)?);
+            }
+        }
+        "json" => {
+            // JSON has no comments, so every value is paired with its origin
+            let values = config.clone().try_into::<BTreeMap<String, JsonValue>>()?;
+            let annotated = values
+                .into_iter()
+                .map(|(key, value)| {
+                    let origin = config_origin(layers, &key);
+                    (key, serde_json::json!({ "value": value, "origin": origin }))
+                })
+                .collect::<JsonMap<String, JsonValue>>();
+            output.push_str(&serde_json::to_string_pretty(&annotated)?);
+            output.push('\n');
+        }
+        format => failure::bail!("{} is not a valid print-config-format!", format),
+    }
+
+    Ok(output)
+}
+
+// The last layer that sets the key is where its value came from
+pub fn config_origin(layers: &[(String, Config)], key: &str) -> String {
+    layers
+        .iter()
+        .rev()
+        .find(|(_, layer)| layer.get::<ConfigValue>(key).is_ok())
+        .map(|(origin, _)| origin.clone())
+        .unwrap_or_else(|| DEFAULTS_ORIGIN.to_string())
+}
+
+// What the value of a config key should look like
+#[derive(Debug, Clone, Copy)]
+enum ConfigKind {
+    Bool,
+    Int(i64, i64),
+    Str,
+    List,
+    OneOf(&'static [&'static str]),
+    Duration,
+    Absolute,
+    PointInTime,
+    Boot,
+}
+
+const CONFIG_SCHEMA: &[(&str, ConfigKind)] = &[
+    ("configs", ConfigKind::List),
+    ("verbose", ConfigKind::Int(0, 9)),
+    ("last-cursor-location", ConfigKind::Str),
+    (
+        "cursor-recovery",
+        ConfigKind::OneOf(&["head", "tail", "timestamp"]),
+    ),
+    ("print-config", ConfigKind::Bool),
+    (
+        "print-config-format",
+        ConfigKind::OneOf(&["yaml", "json", "toml"]),
+    ),
+    ("list-config-files", ConfigKind::Bool),
+    ("check-config", ConfigKind::Bool),
+    ("history-duration", ConfigKind::Duration),
+    ("history-absolute", ConfigKind::Absolute),
+    ("history-count", ConfigKind::Int(i64::MIN, i64::MAX)),
+    ("history-boot", ConfigKind::Boot),
+    (
+        "history-type",
+        ConfigKind::OneOf(&["duration", "absolute", "count", "boot"]),
+    ),
+    ("history-until", ConfigKind::PointInTime),
+    ("oneshot-timeout", ConfigKind::Duration),
+    (
+        "journal-source",
+        ConfigKind::OneOf(&["all", "system", "user"]),
+    ),
+    ("journal-runtime-only", ConfigKind::Bool),
+    ("journal-local-only", ConfigKind::Bool),
+    ("journal-directory", ConfigKind::Str),
+    ("journal-files", ConfigKind::List),
+    ("journal-export", ConfigKind::Str),
+    ("namespaces", ConfigKind::List),
+    ("host-name", ConfigKind::Str),
+    ("host-port", ConfigKind::Int(1, 65534)),
+    (
+        "host-type",
+        ConfigKind::OneOf(&[
+            "filebeat",
+            "journal-remote",
+            "gelf",
+            "fluent-forward",
+            "elasticsearch",
+            "loki",
+            "http",
+        ]),
+    ),
+    ("host-protocol", ConfigKind::OneOf(&["tcp", "udp", "tls"])),
+    ("tls-ca-file", ConfigKind::Str),
+    ("tls-cert-file", ConfigKind::Str),
+    ("tls-key-file", ConfigKind::Str),
+    (
+        "gelf-compression",
+        ConfigKind::OneOf(&["gzip", "zlib", "none"]),
+    ),
+    ("gelf-chunk-size", ConfigKind::Int(256, 65_507)),
+    ("fluent-tag", ConfigKind::Str),
+    ("fluent-require-ack", ConfigKind::Bool),
+    ("fluent-shared-key", ConfigKind::Str),
+    ("elasticsearch-index", ConfigKind::Str),
+    ("elasticsearch-pipeline", ConfigKind::Str),
+    ("elasticsearch-username", ConfigKind::Str),
+    ("elasticsearch-password", ConfigKind::Str),
+    ("elasticsearch-api-key", ConfigKind::Str),
+    ("elasticsearch-dead-letter", ConfigKind::Str),
+    ("loki-labels", ConfigKind::List),
+    ("loki-line-format", ConfigKind::OneOf(&["json", "logfmt"])),
+    ("loki-encoding", ConfigKind::OneOf(&["protobuf", "json"])),
+    ("loki-tenant", ConfigKind::Str),
+    ("loki-dead-letter", ConfigKind::Str),
+    ("http-url", ConfigKind::Str),
+    ("http-method", ConfigKind::OneOf(&["POST", "PUT"])),
+    ("http-headers", ConfigKind::List),
+    ("http-bearer-token-file", ConfigKind::Str),
+    (
+        "http-body-format",
+        ConfigKind::OneOf(&["ndjson", "json-array"]),
+    ),
+    ("http-batch-size", ConfigKind::Int(1, 10_000)),
+    ("http-linger", ConfigKind::Duration),
+    ("http-gzip", ConfigKind::Bool),
+    ("http-dead-letter", ConfigKind::Str),
+    ("main-loop-count", ConfigKind::Int(1, i64::MAX)),
+    ("main-loop-time", ConfigKind::Duration),
+    ("main-loop-message", ConfigKind::Int(1, i64::MAX)),
+    ("exclude-self", ConfigKind::Bool),
+    ("exclude-units", ConfigKind::List),
+];
+
+// Keys that only ever come from the command line, these are not in the schema
+const COMMAND_LINE_KEYS: &[&str] = &[
+    "run-mode",
+    "print-schema",
+    "test-destination",
+    "cursor-command",
+    "cursor-namespace",
+    "cursor-to",
+    "cursor-value",
+];
+
+// A JSON Schema of the config files, for editors that validate YAML
+pub fn config_json_schema() -> JsonValue {
+    let mut properties = JsonMap::new();
+
+    for &(key, kind) in CONFIG_SCHEMA {
+        let property = match kind {
+            ConfigKind::Bool => serde_json::json!({ "type": "boolean" }),
+            ConfigKind::Int(min, max) => {
+                let mut property = serde_json::json!({ "type": "integer" });
+                if min != i64::MIN {
+                    property["minimum"] = min.into();
+                }
+                if max != i64::MAX {
+                    property["maximum"] = max.into();
+                }
+                property
+            }
+            ConfigKind::Str => serde_json::json!({ "type": "string" }),
+            ConfigKind::List => {
+                serde_json::json!({ "type": "array", "items": { "type": "string" } })
+            }
+            ConfigKind::OneOf(allowed) => serde_json::json!({ "enum": allowed }),
+            ConfigKind::Duration => serde_json::json!({ "type": "string" }),
+            ConfigKind::Absolute => serde_json::json!({ "type": "string", "format": "date-time" }),
+            ConfigKind::PointInTime => serde_json::json!({ "type": "string" }),
+            ConfigKind::Boot => serde_json::json!({ "type": ["integer", "string"] }),
+        };
+        properties.insert(key.to_string(), property);
+    }
+
+    serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "journaldeliver config",
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub origin: String,
+    pub key: String,
+    pub message: String,
+}
+
+// Check a single value against its kind, None when it is fine
+fn check_config_value(config: &Config, key: &str, kind: ConfigKind) -> Option<String> {
+    let value = match config.get::<ConfigValue>(key) {
+        Ok(value) => value,
+        Err(_) => return Some("the key is missing".to_string()),
+    };
+    let shown = value.to_string();
+
+    let problem = match kind {
+        ConfigKind::Bool => value
+            .into_bool()
+            .err()
+            .map(|_| "expected true or false".to_string()),
+        ConfigKind::Int(min, max) => match value.into_int() {
+            Ok(number) if number < min || number > max => {
+                Some(format!("expected an integer between {} and {}", min, max))
+            }
+            Ok(_) => None,
+            Err(_) => Some("expected an integer".to_string()),
+        },
+        ConfigKind::Str => value
+            .into_str()
+            .err()
+            .map(|_| "expected a string".to_string()),
+        ConfigKind::List => match value.into_array() {
+            Ok(items) => items
+                .into_iter()
+                .find(|item| item.clone().into_str().is_err())
+                .map(|_| "expected a list of strings".to_string()),
+            Err(_) => Some("expected a list".to_string()),
+        },
+        ConfigKind::OneOf(allowed) => match value.into_str() {
+            Ok(ref text) if allowed.contains(&text.as_str()) => None,
+            _ => Some(format!("expected one of {}", allowed.join(", "))),
+        },
+        ConfigKind::Duration => match value.into_str() {
+            Ok(text) => parse_duration(&text).err().map(|error| error.to_string()),
+            Err(_) => Some("expected a duration such as 10m".to_string()),
+        },
+        ConfigKind::Absolute => match value.into_str() {
+            Ok(text) => text
+                .parse::<DateTime<Utc>>()
+                .err()
+                .map(|error| format!("expected YYYY-MM-DD T HH:mm:SS + TZ, {}", error)),
+            Err(_) => Some("expected a point in time".to_string()),
+        },
+        ConfigKind::PointInTime => match value.into_str() {
+            Ok(ref text) if text.is_empty() => None,
+            Ok(text) => parse_point_in_time(&text)
+                .err()
+                .map(|_| "expected 'now', a duration or YYYY-MM-DD T HH:mm:SS + TZ".to_string()),
+            Err(_) => Some("expected a point in time".to_string()),
+        },
+        ConfigKind::Boot => match value.into_str() {
+            Ok(text) => match text.parse::<i64>() {
+                Ok(offset) if offset <= 0 => None,
+                Ok(_) => Some("expected 0 or a negative boot offset".to_string()),
+                Err(_) => {
+                    let boot_id = text.replace("-", "");
+                    if boot_id.len() == 32 && boot_id.chars().all(|c| c.is_ascii_hexdigit()) {
+                        None
+                    } else {
+                        Some("expected a boot offset or a 128 bit boot id".to_string())
+                    }
+                }
+            },
+            Err(_) => Some("expected a boot offset or a boot id".to_string()),
+        },
+    };
+
+    problem.map(|message| format!("{}, found '{}'", message, shown))
+}
+
+// Check the merged config against the schema, collecting every problem
+pub fn check_config(layers: &[(String, Config)], config: &Config) -> Vec<ConfigProblem> {
+    let mut problems = vec![];
+
+    // A stray variable or file entry must not switch the daemon into a one-off
+    // mode, so these are refused everywhere but on the command line
+    for (origin, layer) in layers.iter() {
+        if origin == COMMAND_LINE_ORIGIN {
+            continue;
+        }
+        for &key in COMMAND_LINE_KEYS {
+            if layer.get::<ConfigValue>(key).is_ok() {
+                problems.push(ConfigProblem {
+                    origin: origin.clone(),
+                    key: key.to_string(),
+                    message: String::from("can only be given on the command line"),
+                });
+            }
+        }
+    }
+
+    let mut add_problem = |key: &str, message: String| {
+        problems.push(ConfigProblem {
+            origin: config_origin(layers, key),
+            key: key.to_string(),
+            message,
+        })
+    };
+
+    for &(key, kind) in CONFIG_SCHEMA {
+        if let Some(message) = check_config_value(config, key, kind) {
+            add_problem(key, message);
+        }
+    }
+
+    let directory = config.get_str("journal-directory").unwrap_or_default();
+    if !directory.is_empty() && !Path::new(&directory).is_dir() {
+        add_problem(
+            "journal-directory",
+            format!("{} is not a directory", directory),
+        );
+    }
+
+    let files = config.get_array("journal-files").unwrap_or_default();
+    for file in files.iter().filter_map(|file| file.clone().into_str().ok()) {
+        if !Path::new(&file).is_file() {
+            add_problem("journal-files", format!("{} is not a file", file));
+        }
+    }
+    if !directory.is_empty() && !files.is_empty() {
+        add_problem(
+            "journal-files",
+            "journal-files and journal-directory can not both be set".to_string(),
+        );
+    }
+
+    // HTTP and acknowledged forward messages both need a stream
+    let host_type = config.get_str("host-type").unwrap_or_default();
+    if [
+        "journal-remote",
+        "fluent-forward",
+        "elasticsearch",
+        "loki",
+        "http",
+    ]
+    .contains(&host_type.as_str())
+        && config.get_str("host-protocol").unwrap_or_default() == "udp"
+    {
+        add_problem(
+            "host-protocol",
+            format!("host-type {} only works over tcp", host_type),
+        );
+    }
+
+    let index = config.get_str("elasticsearch-index").unwrap_or_default();
+    if index_name(&index, Utc::now()).map_or(true, |name| name.is_empty()) {
+        add_problem(
+            "elasticsearch-index",
+            format!("{} is not a valid index template", index),
+        );
+    }
+    let is_set = |key: &str| !config.get_str(key).unwrap_or_default().is_empty();
+    if is_set("elasticsearch-api-key") && is_set("elasticsearch-username") {
+        add_problem(
+            "elasticsearch-api-key",
+            "elasticsearch-api-key and elasticsearch-username can not both be set".to_string(),
+        );
+    }
+
+    let labels = config.get_array("loki-labels").unwrap_or_default();
+    for label in labels
+        .iter()
+        .filter_map(|label| label.clone().into_str().ok())
+    {
+        if parse_label(&label).is_none() {
+            add_problem(
+                "loki-labels",
+                format!("{} is not a label name or name=key", label),
+            );
+        }
+    }
+
+    let url = config.get_str("http-url").unwrap_or_default();
+    // Empty means host-name and host-port
+    if let Err(error) = parse_http_url(&url) {
+        if !url.is_empty() {
+            add_problem("http-url", error.to_string());
+        }
+    }
+    let headers = config.get_array("http-headers").unwrap_or_default();
+    for header in headers
+        .iter()
+        .filter_map(|header| header.clone().into_str().ok())
+    {
+        if parse_header(&header).is_none() {
+            add_problem(
+                "http-headers",
+                format!("{} is not a header such as 'Name: value'", header),
+            );
+        }
+    }
+    let token_file = config.get_str("http-bearer-token-file").unwrap_or_default();
+    if !token_file.is_empty() && !Path::new(&token_file).is_file() {
+        add_problem(
+            "http-bearer-token-file",
+            format!("{} is not a file", token_file),
+        );
+    }
+
+    for key in &["tls-ca-file", "tls-cert-file", "tls-key-file"] {
+        let file = config.get_str(key).unwrap_or_default();
+        if !file.is_empty() && !Path::new(&file).is_file() {
+            add_problem(key, format!("{} is not a file", file));
+        }
+    }
+    if is_set("tls-cert-file") != is_set("tls-key-file") {
+        add_problem(
+            "tls-cert-file",
+            "tls-cert-file and tls-key-file are only used together".to_string(),
+        );
+    }
+
+    // Standard input is given as -
+    let export = config.get_str("journal-export").unwrap_or_default();
+    if !export.is_empty() && export != "-" && !Path::new(&export).is_file() {
+        add_problem("journal-export", format!("{} is not a file", export));
+    }
+
+    problems
+}
+
+// Keys that are set but not in the schema, most likely misspelled
+pub fn unknown_config_keys(layers: &[(String, Config)]) -> Vec<ConfigProblem> {
+    let mut unknown = vec![];
+
+    for (origin, layer) in layers.iter() {
+        let keys = layer
+            .clone()
+            .try_into::<BTreeMap<String, ConfigValue>>()
+            .unwrap_or_default();
+        for key in keys.keys() {
+            let known = CONFIG_SCHEMA
+                .iter()
+                .any(|&(schema_key, _)| schema_key == key)
+                || COMMAND_LINE_KEYS.contains(&key.as_str());
+            if !known {
+                unknown.push(ConfigProblem {
+                    origin: origin.clone(),
+                    key: key.clone(),
+                    message: String::from("unknown key, it is ignored"),
+                });
+            }
+        }
+    }
+
+    unknown
+}
+
+impl Settings {
+    // Deserialize the merged config once it has been checked
+    pub fn from_config(layers: &[(String, Config)], config: &Config) -> Result<Settings> {
+        let problems = check_config(layers, config);
+
+        for problem in problems.iter() {
+            eprintln!(
+                " !! {}: {}: {}",
+                problem.origin, problem.key, problem.message
+            );
+        }
+
+        if !problems.is_empty() {
+            failure::bail!(
+                "Found {} problems in the config, try --check-config",
+                problems.len()
+            );
+        }
+
+        Ok(config.clone().try_into::<Settings>()?)
+    }
+}
+
+// Either 'now', a duration counting back from now or an absolute time
+pub fn parse_point_in_time(value: &str) -> Result<DateTime<Utc>> {
+    let now: DateTime<Utc> = Utc::now();
+
+    match value {
+        "now" => Ok(now),
+        value => match parse_duration(value) {
+            Ok(duration) => now
+                .checked_sub_signed(Duration::from_std(duration)?)
+                .ok_or_else(|| failure::format_err!("{} reaches back too far", value)),
+            Err(_) => Ok(value.parse::<DateTime<Utc>>()?),
+        },
+    }
+}
+
+// Resolve history-until to a point in time, None means follow forever
+pub fn get_history_until(settings: &Settings) -> Result<Option<DateTime<Utc>>> {
+    match settings.history_until.as_str() {
+        "" => Ok(None),
+        until => Ok(Some(parse_point_in_time(until)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults_config() -> Config {
+        let mut config = Config::default();
+        config
+            .merge(ConfigFile::from_str(
+                include_str!("../configs/defaults.yaml"),
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+        config
+    }
+
+    #[test]
+    fn check_config_reports_the_origin_of_each_problem() {
+        let defaults = defaults_config();
+        let mut file = Config::default();
+        file.merge(ConfigFile::from_str(
+            "host-port: 70000\nhistory-type: sideways\nhistory-boot: 1\n",
+            FileFormat::Yaml,
+        ))
+        .unwrap();
+        let mut command_line = Config::default();
+        command_line.set("oneshot-timeout", "soon").unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults),
+            ("bad.yaml".to_string(), file),
+            (COMMAND_LINE_ORIGIN.to_string(), command_line),
+        ];
+        let config = merge_config_layers(layers.clone()).unwrap();
+
+        let problems = check_config(&layers, &config)
+            .into_iter()
+            .map(|problem| (problem.origin, problem.key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                ("bad.yaml".to_string(), "history-boot".to_string()),
+                ("bad.yaml".to_string(), "history-type".to_string()),
+                (
+                    COMMAND_LINE_ORIGIN.to_string(),
+                    "oneshot-timeout".to_string()
+                ),
+                ("bad.yaml".to_string(), "host-port".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_config_accepts_the_defaults() {
+        let config = defaults_config();
+        let layers = vec![(DEFAULTS_ORIGIN.to_string(), config.clone())];
+        assert_eq!(check_config(&layers, &config), vec![]);
+    }
+
+    #[test]
+    fn settings_defaults_match_the_defaults_file() {
+        let config = defaults_config();
+        let layers = vec![(DEFAULTS_ORIGIN.to_string(), config.clone())];
+        assert_eq!(
+            Settings::from_config(&layers, &config).unwrap(),
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn settings_come_from_the_merged_config() {
+        let mut file = Config::default();
+        file.merge(ConfigFile::from_str(
+            "host-port: 9200\nhost-protocol: udp\nhistory-type: boot\nhistory-boot: -1\n\
+             oneshot-timeout: 2m\nexclude-units: [relay.service]\n",
+            FileFormat::Yaml,
+        ))
+        .unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            ("file.yaml".to_string(), file),
+        ];
+        let config = merge_config_layers(layers.clone()).unwrap();
+        let settings = Settings::from_config(&layers, &config).unwrap();
+        assert_eq!(settings.host_port, 9200);
+        assert_eq!(settings.host_protocol, HostProtocol::Udp);
+        assert_eq!(settings.history_type, HistoryType::Boot);
+        assert_eq!(settings.history_boot, "-1");
+        assert_eq!(settings.oneshot_timeout, StdDuration::from_secs(120));
+        assert_eq!(settings.exclude_units, vec![String::from("relay.service")]);
+        assert_eq!(settings.run_mode, None);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_origin() {
+        let mut file = Config::default();
+        file.merge(ConfigFile::from_str(
+            "host-name: example.org\nhost-nmae: typo.example.org\n",
+            FileFormat::Yaml,
+        ))
+        .unwrap();
+        let mut command_line = Config::default();
+        command_line.set("run-mode", "oneshot").unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            ("file.yaml".to_string(), file),
+            (COMMAND_LINE_ORIGIN.to_string(), command_line),
+        ];
+        let unknown = unknown_config_keys(&layers)
+            .into_iter()
+            .map(|problem| (problem.origin, problem.key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unknown,
+            vec![("file.yaml".to_string(), "host-nmae".to_string())]
+        );
+    }
+
+    #[test]
+    fn environment_overrides_files_but_not_the_command_line() {
+        let environment = get_environment_args(vec![
+            (
+                String::from("JOURNALDELIVER_HOST_NAME"),
+                String::from("env.example.org"),
+            ),
+            (
+                String::from("JOURNALDELIVER_HOST_PORT"),
+                String::from("9300"),
+            ),
+            (
+                String::from("JOURNALDELIVER_EXCLUDE_UNITS"),
+                String::from("a.service, b.service"),
+            ),
+            (
+                String::from("HOST_NAME"),
+                String::from("unrelated.example.org"),
+            ),
+        ])
+        .unwrap();
+        let mut file = Config::default();
+        file.merge(ConfigFile::from_str(
+            "host-name: file.example.org\nhost-port: 9200\n",
+            FileFormat::Yaml,
+        ))
+        .unwrap();
+        let mut command_line = Config::default();
+        command_line.set("host-port", 9400).unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            ("file.yaml".to_string(), file),
+            (ENVIRONMENT_ORIGIN.to_string(), environment),
+            (COMMAND_LINE_ORIGIN.to_string(), command_line),
+        ];
+        let config = merge_config_layers(layers.clone()).unwrap();
+        let settings = Settings::from_config(&layers, &config).unwrap();
+        assert_eq!(settings.host_name, "env.example.org");
+        assert_eq!(settings.host_port, 9400);
+        assert_eq!(settings.exclude_units, vec!["a.service", "b.service"]);
+        assert_eq!(config_origin(&layers, "host-name"), ENVIRONMENT_ORIGIN);
+        assert_eq!(config_origin(&layers, "host-port"), COMMAND_LINE_ORIGIN);
+        assert_eq!(config_origin(&layers, "host-type"), DEFAULTS_ORIGIN);
+        assert!(config_with_origins(&layers, &config, "yaml")
+            .unwrap()
+            .contains("# environment\nhost-name: env.example.org\n"));
+    }
+
+    #[test]
+    fn an_empty_environment_still_merges() {
+        let environment = get_environment_args(vec![]).unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            (ENVIRONMENT_ORIGIN.to_string(), environment),
+        ];
+        assert!(merge_config_layers(layers).is_ok());
+    }
+
+    #[test]
+    fn run_mode_is_refused_from_the_environment() {
+        let environment = get_environment_args(vec![(
+            String::from("JOURNALDELIVER_RUN_MODE"),
+            String::from("oneshot"),
+        )])
+        .unwrap();
+        let mut command_line = Config::default();
+        command_line.set("run-mode", "daemon").unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            (ENVIRONMENT_ORIGIN.to_string(), environment),
+            (COMMAND_LINE_ORIGIN.to_string(), command_line),
+        ];
+        let config = merge_config_layers(layers.clone()).unwrap();
+        assert_eq!(
+            check_config(&layers, &config),
+            vec![ConfigProblem {
+                origin: ENVIRONMENT_ORIGIN.to_string(),
+                key: String::from("run-mode"),
+                message: String::from("can only be given on the command line"),
+            }]
+        );
+    }
+
+    #[test]
+    fn wildcards_match_file_names() {
+        assert!(wildcard_match("*.yaml", "10-destination.yaml"));
+        assert!(wildcard_match("1?-*.yaml", "10-destination.yaml"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("*.yaml", "10-destination.yaml.bak"));
+        assert!(!wildcard_match("2*.yaml", "10-destination.yaml"));
+    }
+
+    #[test]
+    fn conf_d_entries_expand_in_lexical_order() {
+        let directory =
+            std::env::temp_dir().join(format!("journaldeliver-conf.d-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in &[
+            "20-filters.yaml",
+            "10-destination.yaml",
+            "notes.txt",
+            ".10-swap.yaml",
+        ] {
+            std::fs::write(directory.join(name), "verbose: 1\n").unwrap();
+        }
+        let in_directory = |name: &str| directory.join(name).to_string_lossy().to_string();
+
+        let pattern = expand_config_path(&in_directory("*.yaml"));
+        let whole = expand_config_path(&directory.to_string_lossy());
+        let plain = expand_config_path(&in_directory("missing.yaml"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            pattern,
+            vec![
+                in_directory("10-destination.yaml"),
+                in_directory("20-filters.yaml")
+            ]
+        );
+        assert_eq!(whole, pattern);
+        assert_eq!(plain, vec![in_directory("missing.yaml")]);
+    }
+
+    #[test]
+    fn print_config_annotates_every_format_with_origins() {
+        let mut file = Config::default();
+        file.merge(ConfigFile::from_str(
+            "host-name: file.example.org\n",
+            FileFormat::Yaml,
+        ))
+        .unwrap();
+        let layers = vec![
+            (DEFAULTS_ORIGIN.to_string(), defaults_config()),
+            ("file.yaml".to_string(), file),
+        ];
+        let config = merge_config_layers(layers.clone()).unwrap();
+
+        let yaml = config_with_origins(&layers, &config, "yaml").unwrap();
+        assert!(yaml.contains("# file.yaml\nhost-name: file.example.org\n"));
+        assert!(yaml.contains("# defaults\nhost-port: 9000\n"));
+
+        let toml = config_with_origins(&layers, &config, "toml").unwrap();
+        assert!(toml.contains("# file.yaml\nhost-name = \"file.example.org\"\n"));
+        assert!(toml.contains("# defaults\nhost-port = 9000\n"));
+
+        let json: JsonValue =
+            serde_json::from_str(&config_with_origins(&layers, &config, "json").unwrap()).unwrap();
+        assert_eq!(json["host-name"]["value"], "file.example.org");
+        assert_eq!(json["host-name"]["origin"], "file.yaml");
+        assert_eq!(json["host-port"]["origin"], DEFAULTS_ORIGIN);
+    }
+
+    #[test]
+    fn a_configs_entry_that_is_not_a_path_is_an_error() {
+        let mut command_line = Config::default();
+        command_line
+            .merge(ConfigFile::from_str(
+                "configs:\n  - nested: entry\n",
+                FileFormat::Yaml,
+            ))
+            .unwrap();
+        let error = get_config_layers(command_line).unwrap_err();
+        assert_eq!(error.to_string(), "configs: expected a list of strings");
+    }
+
+    #[test]
+    fn points_in_time_before_the_calendar_are_an_error() {
+        assert!(parse_point_in_time("1h").unwrap() < Utc::now());
+        assert!(parse_point_in_time("1000000 years").is_err());
+    }
+
+    #[test]
+    fn exported_schema_is_up_to_date() {
+        let exported: JsonValue =
+            serde_json::from_str(include_str!("../configs/journaldeliver.schema.json")).unwrap();
+        assert_eq!(exported, config_json_schema());
+    }
+}
@@ -0,0 +1,710 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+use chrono::Utc;
+
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration as StdDuration, Instant as StdInstant},
+};
+
+use crate::{
+    cursor::CursorRecord,
+    elasticsearch::ElasticsearchSink,
+    fluent::FluentForwardSink,
+    gelf::GelfSink,
+    http_sink::HttpSink,
+    journal_remote::JournalRemoteSink,
+    loki::LokiSink,
+    pipeline::{Event, Sink},
+    settings::{HostProtocol, HostType, Settings},
+    tls::{negotiated, tls_connect, TlsFiles, TlsStream},
+    Result,
+};
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct HostRecord {
+    pub host: String,
+    pub port: u16,
+    pub protocol: HostProtocol,
+    pub kind: HostType,
+    pub tls: TlsFiles,
+}
+
+impl HostRecord {
+    pub fn from_settings(settings: &Settings) -> HostRecord {
+        HostRecord {
+            host: settings.host_name.clone(),
+            port: settings.host_port,
+            protocol: settings.host_protocol,
+            kind: settings.host_type,
+            tls: TlsFiles::from_settings(settings),
+        }
+    }
+
+    // Such as "filebeat tcp://127.0.0.1:9000"
+    pub fn label(&self) -> String {
+        let kind = serde_json::to_value(self.kind).unwrap_or_default();
+        let protocol = serde_json::to_value(self.protocol).unwrap_or_default();
+        format!(
+            "{} {}://{}:{}",
+            kind.as_str().unwrap_or_default(),
+            protocol.as_str().unwrap_or_default(),
+            self.host,
+            self.port
+        )
+    }
+}
+
+// Exactly what goes on the wire for a record, one JSON document per line
+pub fn wire_bytes(value: &JsonValue) -> Vec<u8> {
+    format!("{}\n", value).into_bytes()
+}
+
+// How long to wait for a TCP connection to be accepted
+pub const CONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+// How long to wait for an answer from the remote host
+pub const READ_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+// A connection to the remote host, each write to UDP is one datagram
+pub enum RemoteStream {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    Tls(Box<TlsStream>),
+}
+
+impl RemoteStream {
+    pub fn take_error(&self) -> std::io::Result<Option<std::io::Error>> {
+        match self {
+            RemoteStream::Tcp(stream) => stream.take_error(),
+            RemoteStream::Udp(socket) => socket.take_error(),
+            RemoteStream::Tls(stream) => stream.sock.take_error(),
+        }
+    }
+}
+
+impl Read for RemoteStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            RemoteStream::Tcp(stream) => stream.read(buf),
+            RemoteStream::Udp(socket) => socket.recv(buf),
+            RemoteStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for RemoteStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            RemoteStream::Tcp(stream) => stream.write(buf),
+            RemoteStream::Udp(socket) => socket.send(buf),
+            RemoteStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            RemoteStream::Tcp(stream) => stream.flush(),
+            RemoteStream::Udp(_) => Ok(()),
+            RemoteStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+fn resolve_remote_host(connection: &HostRecord) -> Result<Vec<SocketAddr>> {
+    Ok((connection.host.as_str(), connection.port)
+        .to_socket_addrs()?
+        .collect())
+}
+
+// Try every resolved address in turn, returning the first that connects
+fn connect_to_remote_host(
+    connection: &HostRecord,
+    addresses: &[SocketAddr],
+) -> Result<(RemoteStream, SocketAddr)> {
+    let mut last_error = String::from("the host did not resolve to any address");
+
+    for address in addresses {
+        let tcp_stream = || -> Result<TcpStream> {
+            let stream = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            Ok(stream)
+        };
+        let result = match connection.protocol {
+            HostProtocol::Tcp => tcp_stream().map(RemoteStream::Tcp),
+            HostProtocol::Tls => tcp_stream()
+                .and_then(|stream| tls_connect(&connection.tls, &connection.host, stream))
+                .map(|stream| RemoteStream::Tls(Box::new(stream))),
+            HostProtocol::Udp => {
+                let local: SocketAddr = if address.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                UdpSocket::bind(local)
+                    .and_then(|socket| socket.connect(address).map(|_| socket))
+                    .map(RemoteStream::Udp)
+                    .map_err(failure::Error::from)
+            }
+        };
+        match result {
+            Ok(stream) => return Ok((stream, *address)),
+            Err(error) => last_error = format!("{}: {}", address, error),
+        }
+    }
+
+    failure::bail!(
+        "Could not connect to {}, {}",
+        connection.label(),
+        last_error
+    )
+}
+
+// The connection of a sink, made on first use and again after any failure.
+// What it resolved to and connected to is kept for test-destination.
+pub struct RemoteConnection {
+    pub host: HostRecord,
+    pub resolved: Vec<SocketAddr>,
+    pub connected: Option<SocketAddr>,
+    stream: Option<RemoteStream>,
+}
+
+impl RemoteConnection {
+    pub fn new(host: HostRecord) -> RemoteConnection {
+        RemoteConnection {
+            host,
+            resolved: vec![],
+            connected: None,
+            stream: None,
+        }
+    }
+
+    pub fn stream(&mut self) -> Result<&mut RemoteStream> {
+        if self.stream.is_none() {
+            self.resolved = resolve_remote_host(&self.host).map_err(|error| {
+                failure::format_err!("Could not resolve {}, {}", self.host.host, error)
+            })?;
+            let (stream, address) = connect_to_remote_host(&self.host, &self.resolved)?;
+            self.connected = Some(address);
+            self.stream = Some(stream);
+        }
+        match self.stream.as_mut() {
+            Some(stream) => Ok(stream),
+            None => failure::bail!("Not connected to {}", self.host.label()),
+        }
+    }
+
+    // The TLS version and cipher suite of the stream, if it is TLS
+    pub fn negotiated(&self) -> Option<String> {
+        match &self.stream {
+            Some(RemoteStream::Tls(stream)) => Some(negotiated(stream)),
+            _ => None,
+        }
+    }
+
+    // Drop the stream so that the next use connects again
+    pub fn disconnect(&mut self) {
+        self.stream = None;
+        self.connected = None;
+    }
+
+    pub fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        let written = self.stream().and_then(|stream| {
+            stream.write_all(bytes)?;
+            stream.flush()?;
+            match stream.take_error()? {
+                Some(error) => Err(error.into()),
+                None => Ok(()),
+            }
+        });
+        if written.is_err() {
+            self.disconnect();
+        }
+        written
+    }
+}
+
+// Sends JSON lines to filebeat
+pub struct FilebeatSink {
+    connection: RemoteConnection,
+}
+
+impl FilebeatSink {
+    pub fn new(host: HostRecord) -> FilebeatSink {
+        FilebeatSink {
+            connection: RemoteConnection::new(host),
+        }
+    }
+}
+
+impl Sink for FilebeatSink {
+    fn label(&self) -> String {
+        self.connection.host.label()
+    }
+
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        Ok(batch
+            .iter()
+            .flat_map(|event| wire_bytes(&event.value))
+            .collect())
+    }
+
+    // Part of a batch may be sent twice when the connection fails midway.
+    // Over UDP each record is a datagram of its own, a whole batch would not
+    // fit in one.
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        match self.connection.host.protocol {
+            HostProtocol::Tcp | HostProtocol::Tls => {
+                let bytes = self.encode(batch)?;
+                self.connection.write_all(&bytes)?;
+            }
+            HostProtocol::Udp => {
+                for event in batch {
+                    self.connection.write_all(&wire_bytes(&event.value))?;
+                }
+            }
+        }
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn connection(&mut self) -> Option<&mut RemoteConnection> {
+        Some(&mut self.connection)
+    }
+
+    // Filebeat's JSON lines carry no acknowledgement back to the sender
+    fn acknowledgement(&self) -> String {
+        String::from("not supported by filebeat")
+    }
+}
+
+// A binary payload as offset, hex bytes and printable characters, 16 bytes to
+// a line like hexdump -C
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::default();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let printable: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7e => byte as char,
+                _ => '.',
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            printable
+        ));
+    }
+    dump
+}
+
+// Print what would go on the wire, labelled with the destination, instead of
+// connecting. Batches are made as the inner sink would make them and every
+// record is acknowledged as if it had been sent.
+pub struct DryRunSink {
+    inner: Box<dyn Sink>,
+}
+
+impl DryRunSink {
+    pub fn new(inner: Box<dyn Sink>) -> DryRunSink {
+        DryRunSink { inner }
+    }
+}
+
+impl Sink for DryRunSink {
+    fn label(&self) -> String {
+        self.inner.label()
+    }
+
+    // Text payloads get the label on every line, anything else such as
+    // compressed or MessagePack bodies is hex dumped under it
+    fn encode(&self, batch: &[Event]) -> Result<Vec<u8>> {
+        let label = self.inner.label();
+        let bytes = self.inner.encode(batch)?;
+
+        let printed = match std::str::from_utf8(&bytes) {
+            Ok(text)
+                if !text
+                    .chars()
+                    .any(|c| c.is_control() && !['\n', '\r', '\t'].contains(&c)) =>
+            {
+                text.lines()
+                    .map(|line| format!("[{}] {}\n", label, line))
+                    .collect()
+            }
+            _ => format!("[{}] {} bytes\n{}", label, bytes.len(), hex_dump(&bytes)),
+        };
+        Ok(printed.into_bytes())
+    }
+
+    fn send(&mut self, batch: &[Event]) -> Result<Vec<CursorRecord>> {
+        let bytes = self.encode(batch)?;
+        let stdout = std::io::stdout();
+        let mut handle = stdout.lock();
+        handle.write_all(&bytes)?;
+        handle.flush()?;
+        Ok(batch.iter().map(|event| event.cursor.clone()).collect())
+    }
+
+    fn max_batch(&self) -> usize {
+        self.inner.max_batch()
+    }
+
+    fn linger(&self) -> StdDuration {
+        self.inner.linger()
+    }
+}
+
+// The sink for host-type
+pub fn sink_from_settings(settings: &Settings) -> Box<dyn Sink> {
+    let connection = HostRecord::from_settings(settings);
+
+    match connection.kind {
+        HostType::Filebeat => Box::new(FilebeatSink::new(connection)),
+        HostType::JournalRemote => Box::new(JournalRemoteSink::new(connection)),
+        HostType::Gelf => Box::new(GelfSink::new(
+            connection,
+            settings.gelf_compression,
+            settings.gelf_chunk_size,
+        )),
+        HostType::FluentForward => Box::new(FluentForwardSink::new(
+            connection,
+            &settings.fluent_tag,
+            settings.fluent_require_ack,
+            &settings.fluent_shared_key,
+        )),
+        HostType::Elasticsearch => Box::new(ElasticsearchSink::new(connection, settings)),
+        HostType::Loki => Box::new(LokiSink::new(connection, settings)),
+        HostType::Http => Box::new(HttpSink::new(connection, settings)),
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct DestinationReport {
+    pub destination: String,
+    pub resolved: Vec<String>,
+    pub connected: Option<String>,
+    pub connect_latency: Option<String>,
+    pub tls: Option<String>,
+    pub sent_bytes: usize,
+    pub send_latency: Option<String>,
+    pub acknowledgement: String,
+    pub error: Option<String>,
+}
+
+// A record that is easy to find and drop again at the collector
+fn test_record() -> JsonValue {
+    let timestamp_str = Utc::now().to_rfc3339().replace("+00:00", "Z");
+    let mut json_map = JsonMap::new();
+    json_map.insert("@timestamp".into(), timestamp_str.into());
+    json_map.insert("journald.test".into(), true.into());
+    json_map.insert(
+        "message".into(),
+        format!(
+            "journaldeliver {} connectivity test, this record can be dropped",
+            env!("CARGO_PKG_VERSION")
+        )
+        .into(),
+    );
+    json_map.into()
+}
+
+// Connect and send a test record through the sink of host-type, every
+// failure ends up in the report rather than in an error
+pub fn test_destination(settings: &Settings) -> DestinationReport {
+    let mut sink = sink_from_settings(settings);
+    let mut report = DestinationReport {
+        destination: sink.label(),
+        ..DestinationReport::default()
+    };
+
+    // The HTTP host types connect inside the client for each request, so a
+    // connection of the same host is made ahead of it for the report
+    let mut preflight = sink
+        .http_client()
+        .map(|client| RemoteConnection::new(client.host.clone()));
+    let connection = match preflight.as_mut() {
+        Some(connection) => Some(connection),
+        None => sink.connection(),
+    };
+    if let Some(connection) = connection {
+        let started = StdInstant::now();
+        let connected = connection.stream().map(|_| ());
+        report.resolved = connection
+            .resolved
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        if let Err(error) = connected {
+            report.error = Some(error.to_string());
+            return report;
+        }
+        report.connected = connection.connected.map(|address| address.to_string());
+        report.connect_latency = Some(format!("{:?}", started.elapsed()));
+        report.tls = connection.negotiated();
+    }
+    // Closed again before the request, which makes its own
+    drop(preflight);
+
+    let batch = [Event {
+        value: test_record(),
+        cursor: CursorRecord::default(),
+        fields: BTreeMap::new(),
+    }];
+    let started = StdInstant::now();
+    let sent = sink.encode(&batch).and_then(|bytes| {
+        sink.send(&batch)?;
+        Ok(bytes.len())
+    });
+    match sent {
+        Ok(sent_bytes) => {
+            report.sent_bytes = sent_bytes;
+            report.send_latency = Some(format!("{:?}", started.elapsed()));
+            report.acknowledgement = sink.acknowledgement();
+        }
+        Err(error) => report.error = Some(format!("Could not send the test record, {}", error)),
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_output_is_labelled_with_the_destination() {
+        let settings = Settings {
+            host_name: String::from("10.0.0.7"),
+            host_port: 5044,
+            host_protocol: HostProtocol::Udp,
+            ..Settings::default()
+        };
+        assert_eq!(
+            HostRecord::from_settings(&settings).label(),
+            "filebeat udp://10.0.0.7:5044"
+        );
+        assert_eq!(
+            wire_bytes(&serde_json::json!({ "message": "hello" })),
+            b"{\"message\":\"hello\"}\n".to_vec()
+        );
+    }
+
+    fn hello_batch(count: usize) -> Vec<Event> {
+        (0..count)
+            .map(|_| Event {
+                value: serde_json::json!({ "message": "hello" }),
+                cursor: CursorRecord::default(),
+                fields: BTreeMap::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dry_run_prints_whole_batches_of_the_inner_sink() {
+        let settings = Settings {
+            host_type: HostType::Http,
+            http_body_format: crate::settings::HttpBodyFormat::JsonArray,
+            http_batch_size: 7,
+            ..Settings::default()
+        };
+        let dry_run = DryRunSink::new(sink_from_settings(&settings));
+        assert_eq!(dry_run.max_batch(), 7);
+        assert_eq!(
+            String::from_utf8(dry_run.encode(&hello_batch(2)).unwrap()).unwrap(),
+            "[http http://127.0.0.1:9000/] [{\"message\":\"hello\"},{\"message\":\"hello\"}]\n"
+        );
+
+        let dry_run = DryRunSink::new(sink_from_settings(&Settings::default()));
+        assert_eq!(
+            String::from_utf8(dry_run.encode(&hello_batch(2)).unwrap())
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn dry_run_hex_dumps_binary_payloads() {
+        let settings = Settings {
+            host_type: HostType::FluentForward,
+            ..Settings::default()
+        };
+        let dry_run = DryRunSink::new(sink_from_settings(&settings));
+        let printed = String::from_utf8(dry_run.encode(&hello_batch(1)).unwrap()).unwrap();
+        let mut lines = printed.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("[fluent-forward tcp://127.0.0.1:9000] "));
+        assert!(lines.next().unwrap().starts_with("00000000  93 "));
+
+        assert_eq!(
+            hex_dump(b"\x1f\x8bhi"),
+            format!("00000000  {:<47}  |..hi|\n", "1f 8b 68 69")
+        );
+    }
+
+    #[test]
+    fn each_record_is_a_datagram_over_udp() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            host_port: receiver.local_addr().unwrap().port(),
+            host_protocol: HostProtocol::Udp,
+            ..Settings::default()
+        };
+        let mut sink = sink_from_settings(&settings);
+        sink.send(&hello_batch(3)).unwrap();
+
+        let mut buffer = [0u8; 65_536];
+        for _ in 0..3 {
+            let size = receiver.recv(&mut buffer).unwrap();
+            assert_eq!(&buffer[..size], b"{\"message\":\"hello\"}\n");
+        }
+    }
+
+    #[test]
+    fn test_destination_sends_a_marked_record() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            host_port: listener.local_addr().unwrap().port(),
+            ..Settings::default()
+        };
+        let report = test_destination(&settings);
+        assert_eq!(report.error, None);
+        assert!(report.connect_latency.is_some());
+
+        let mut received = String::default();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_string(&mut received)
+            .unwrap();
+        assert_eq!(report.sent_bytes, received.len());
+        let record: JsonValue = serde_json::from_str(received.trim_end()).unwrap();
+        assert_eq!(record["journald.test"], true);
+    }
+
+    // Accepts one connection that has to present a client certificate, and
+    // returns what was sent over it
+    fn tls_server(listener: std::net::TcpListener) -> std::thread::JoinHandle<String> {
+        use rustls::ServerConnection;
+        use std::sync::Arc;
+
+        let config = crate::tls::test_server_config();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls_stream = rustls::StreamOwned::new(connection, stream);
+            let mut received = String::default();
+            let _ = tls_stream.read_to_string(&mut received);
+            received
+        })
+    }
+
+    #[test]
+    fn test_destination_connects_with_tls() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            host_name: String::from("localhost"),
+            host_port: listener.local_addr().unwrap().port(),
+            host_protocol: HostProtocol::Tls,
+            tls_ca_file: crate::tls::test_fixture("ca.pem"),
+            tls_cert_file: crate::tls::test_fixture("client.pem"),
+            tls_key_file: crate::tls::test_fixture("client.key"),
+            ..Settings::default()
+        };
+        let server = tls_server(listener);
+        let report = test_destination(&settings);
+        assert_eq!(report.error, None);
+        assert_eq!(
+            report.destination,
+            format!("filebeat tls://localhost:{}", settings.host_port)
+        );
+        assert!(report.tls.unwrap().starts_with("TLSv1_3 "));
+
+        let received = server.join().unwrap();
+        let record: JsonValue = serde_json::from_str(received.trim_end()).unwrap();
+        assert_eq!(record["journald.test"], true);
+    }
+
+    #[test]
+    fn test_destination_reports_an_untrusted_certificate() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            host_name: String::from("localhost"),
+            host_port: listener.local_addr().unwrap().port(),
+            host_protocol: HostProtocol::Tls,
+            ..Settings::default()
+        };
+        let _server = tls_server(listener);
+        let report = test_destination(&settings);
+        assert_eq!(report.connected, None);
+        let error = report.error.unwrap();
+        assert!(error.contains("Could not connect"), "{}", error);
+        assert!(error.contains("UnknownIssuer"), "{}", error);
+    }
+
+    #[test]
+    fn test_destination_connects_ahead_of_an_http_request() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            host_port: listener.local_addr().unwrap().port(),
+            host_type: HostType::JournalRemote,
+            ..Settings::default()
+        };
+        let server = crate::http::serve_requests(
+            listener,
+            vec![String::from(
+                "HTTP/1.1 202 Accepted\r\nContent-Length: 3\r\n\r\nOK.",
+            )],
+        );
+        let report = test_destination(&settings);
+        assert_eq!(report.error, None);
+        assert_eq!(
+            report.resolved,
+            vec![format!("127.0.0.1:{}", settings.host_port)]
+        );
+        assert_eq!(report.connected, Some(report.resolved[0].clone()));
+        assert!(report.connect_latency.is_some());
+        assert_eq!(report.acknowledgement, "HTTP/1.1 202 Accepted");
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_destination_reports_a_refused_connection() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let settings = Settings {
+            host_port: port,
+            ..Settings::default()
+        };
+        let report = test_destination(&settings);
+        assert_eq!(report.connected, None);
+        assert_eq!(report.sent_bytes, 0);
+        assert!(report.error.unwrap().contains("Could not connect"));
+    }
+}
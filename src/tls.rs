@@ -0,0 +1,175 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// TLS for host-protocol tls. The server is checked against the Mozilla roots
+// unless tls-ca-file names the certificates to trust instead, a client
+// certificate is only presented when tls-cert-file is set.
+
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+
+use ureq::tls::{Certificate, ClientCert, PrivateKey, RootCerts, TlsConfig};
+
+use std::{convert::TryFrom, net::TcpStream, sync::Arc};
+
+use crate::{settings::Settings, Result};
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct TlsFiles {
+    pub ca_file: String,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+impl TlsFiles {
+    pub fn from_settings(settings: &Settings) -> TlsFiles {
+        TlsFiles {
+            ca_file: settings.tls_ca_file.clone(),
+            cert_file: settings.tls_cert_file.clone(),
+            key_file: settings.tls_key_file.clone(),
+        }
+    }
+}
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+pub fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|error| {
+            failure::format_err!("{:?}\nwhile trying to read file: {}", error, path)
+        })?;
+    if certificates.is_empty() {
+        failure::bail!("There are no certificates in {}", path);
+    }
+    Ok(certificates)
+}
+
+pub fn read_private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|error| failure::format_err!("{:?}\nwhile trying to read file: {}", error, path))
+}
+
+pub fn client_config(files: &TlsFiles) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    if files.ca_file.is_empty() {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    } else {
+        for certificate in read_certificates(&files.ca_file)? {
+            roots.add(certificate)?;
+        }
+    }
+
+    let builder =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+    let config = if files.cert_file.is_empty() {
+        builder.with_no_client_auth()
+    } else {
+        builder.with_client_auth_cert(
+            read_certificates(&files.cert_file)?,
+            read_private_key(&files.key_file)?,
+        )?
+    };
+
+    Ok(Arc::new(config))
+}
+
+// The same trust and client certificate for the host types that go over
+// HTTP, which ureq builds its own rustls configuration from
+pub fn ureq_tls_config(files: &TlsFiles) -> Result<TlsConfig> {
+    let owned = |certificates: Vec<CertificateDer<'static>>| -> Vec<Certificate<'static>> {
+        certificates
+            .iter()
+            .map(|certificate| Certificate::from_der(certificate.as_ref()).to_owned())
+            .collect()
+    };
+
+    let mut builder = TlsConfig::builder();
+    if !files.ca_file.is_empty() {
+        let roots = owned(read_certificates(&files.ca_file)?);
+        builder = builder.root_certs(RootCerts::new_with_certs(&roots));
+    }
+    if !files.cert_file.is_empty() {
+        let chain = owned(read_certificates(&files.cert_file)?);
+        let key = std::fs::read(&files.key_file)
+            .map_err(failure::Error::from)
+            .and_then(|pem| Ok(PrivateKey::from_pem(&pem)?))
+            .map_err(|error| {
+                failure::format_err!("{:?}\nwhile trying to read file: {}", error, files.key_file)
+            })?;
+        builder = builder.client_cert(Some(ClientCert::new_with_certs(&chain, key)));
+    }
+
+    Ok(builder.build())
+}
+
+// The handshake is done here, so that a refused certificate is a failure to
+// connect rather than to send
+pub fn tls_connect(files: &TlsFiles, host: &str, mut stream: TcpStream) -> Result<TlsStream> {
+    let server_name = ServerName::try_from(host.to_string())?;
+    let mut connection = ClientConnection::new(client_config(files)?, server_name)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+
+    Ok(StreamOwned::new(connection, stream))
+}
+
+// Such as "TLSv1_3 TLS13_AES_256_GCM_SHA384"
+pub fn negotiated(stream: &TlsStream) -> String {
+    let version = stream
+        .conn
+        .protocol_version()
+        .map(|version| format!("{:?}", version))
+        .unwrap_or_default();
+    let suite = stream
+        .conn
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_default();
+    format!("{} {}", version, suite)
+}
+
+#[cfg(test)]
+pub fn test_fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// A server with the certificate of the fixtures, which only takes clients
+// that present one signed by the fixture CA
+#[cfg(test)]
+pub fn test_server_config() -> rustls::ServerConfig {
+    use rustls::server::WebPkiClientVerifier;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(&test_fixture("ca.pem")).unwrap() {
+        roots.add(certificate).unwrap();
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .unwrap();
+    rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            read_certificates(&test_fixture("server.pem")).unwrap(),
+            read_private_key(&test_fixture("server.key")).unwrap(),
+        )
+        .unwrap()
+}
@@ -0,0 +1,298 @@
+// Copyright 2018 Andre Stemmet
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+// http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express
+// or implied. See the License for the specific language governing
+// permissions and limitations under the License.
+
+// The whole pipeline, from a fixture through the filebeat sink into a local
+// listener, with the cursor saved the way the daemon saves it
+
+use journaldeliver::{
+    cursor::{read_cursor_state, read_write_cursor_thread, CursorRecord},
+    export::read_export_entry,
+    memory::MemorySource,
+    pipeline::{forward, run_sink, Event, ExcludeRecord, Source, Transform},
+    settings::Settings,
+    sink::sink_from_settings,
+};
+
+use serde_json::Value as JsonValue;
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    net::TcpListener,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration as StdDuration, Instant as StdInstant},
+};
+
+// Collects everything sent to it, over any number of connections
+struct Collector {
+    received: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Collector {
+    fn start(listener: TcpListener) -> Collector {
+        let received = Arc::new(Mutex::new(vec![]));
+        let shared = received.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().filter_map(Result::ok) {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut buffer = [0u8; 4096];
+                    while let Ok(read) = stream.read(&mut buffer) {
+                        if read == 0 {
+                            break;
+                        }
+                        shared.lock().unwrap().extend_from_slice(&buffer[..read]);
+                    }
+                });
+            }
+        });
+        Collector { received }
+    }
+
+    // The messages received so far, waiting until there are at least `count`
+    fn messages(&self, count: usize) -> Vec<String> {
+        let started = StdInstant::now();
+        loop {
+            let received = String::from_utf8(self.received.lock().unwrap().clone()).unwrap();
+            let messages: Vec<String> = received
+                .lines()
+                .map(|line| serde_json::from_str::<JsonValue>(line).unwrap())
+                .map(|record| record["message"].as_str().unwrap_or_default().to_string())
+                .collect();
+            if messages.len() >= count || started.elapsed() > StdDuration::from_secs(10) {
+                return messages;
+            }
+            thread::sleep(StdDuration::from_millis(50));
+        }
+    }
+}
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn cursor_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "journaldeliver-{}-{}.yaml",
+        name,
+        std::process::id()
+    ));
+    std::fs::remove_file(&path).unwrap_or_default();
+    path.to_string_lossy().to_string()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Run the source to its end and wait until the cursor file is written
+fn deliver(
+    source: &mut dyn Source,
+    transforms: &mut [Box<dyn Transform>],
+    port: u16,
+    path: &str,
+) -> u64 {
+    let settings = Settings {
+        host_port: port,
+        ..Settings::default()
+    };
+    let mut sink = sink_from_settings(&settings);
+    let (event_sender, event_receiver) = mpsc::sync_channel::<Event>(300);
+    let (cursor_sender, cursor_receiver) = mpsc::sync_channel::<CursorRecord>(300);
+    let sink_thread =
+        thread::spawn(move || run_sink(sink.as_mut(), &event_receiver, &cursor_sender));
+    let path = path.to_string();
+    let initial = read_cursor_state(&path);
+    let cursor_thread =
+        thread::spawn(move || read_write_cursor_thread(&path, initial, &cursor_receiver));
+
+    let sent = forward(source, transforms, &event_sender).unwrap();
+    drop(event_sender);
+    sink_thread.join().unwrap();
+    cursor_thread.join().unwrap();
+
+    sent
+}
+
+fn all_messages() -> Vec<&'static str> {
+    vec![
+        "app 1",
+        "noisy 1",
+        "app 2",
+        "app 3\nwith a second line",
+        "noisy 2",
+        "app 4",
+    ]
+}
+
+#[test]
+fn records_and_cursor_reach_the_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let collector = Collector::start(listener);
+    let path = cursor_path("deliver");
+
+    let mut source = MemorySource::from_export_file("", &fixture("units.export")).unwrap();
+    assert_eq!(deliver(&mut source, &mut [], port, &path), 6);
+
+    assert_eq!(collector.messages(6), all_messages());
+    assert_eq!(
+        read_cursor_state(&path).get(""),
+        MemorySource::cursor(6, 1_792_326_640_000_000)
+    );
+}
+
+#[test]
+fn a_restart_resumes_after_the_saved_cursor() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let collector = Collector::start(listener);
+    let path = cursor_path("restart");
+
+    // The first run stops after three records, as if it had been killed
+    let mut reader = BufReader::new(File::open(fixture("units.export")).unwrap());
+    let mut records = vec![];
+    while let Some(entry) = read_export_entry(&mut reader).unwrap() {
+        records.push(entry);
+    }
+    let mut interrupted = MemorySource::new("", records[..3].to_vec());
+    assert_eq!(deliver(&mut interrupted, &mut [], port, &path), 3);
+    let saved = read_cursor_state(&path).get("").to_string();
+    assert_eq!(saved, MemorySource::cursor(3, 1_792_326_637_000_000));
+
+    let mut resumed = MemorySource::from_export_file("", &fixture("units.export"))
+        .unwrap()
+        .after(&saved);
+    assert_eq!(deliver(&mut resumed, &mut [], port, &path), 3);
+
+    // Nothing is lost and nothing is sent twice
+    assert_eq!(collector.messages(6), all_messages());
+    assert_eq!(
+        read_cursor_state(&path).get(""),
+        MemorySource::cursor(6, 1_792_326_640_000_000)
+    );
+}
+
+#[test]
+fn records_are_held_until_the_listener_comes_back() {
+    let port = free_port();
+    let path = cursor_path("reconnect");
+
+    // Nobody listens yet, the sink keeps retrying the first batch
+    let listening = thread::spawn(move || {
+        thread::sleep(StdDuration::from_millis(2_000));
+        Collector::start(TcpListener::bind(("127.0.0.1", port)).unwrap())
+    });
+
+    let mut source = MemorySource::from_export_file("", &fixture("units.export")).unwrap();
+    assert_eq!(deliver(&mut source, &mut [], port, &path), 6);
+
+    let collector = listening.join().unwrap();
+    assert_eq!(collector.messages(6), all_messages());
+    assert_eq!(
+        read_cursor_state(&path).get(""),
+        MemorySource::cursor(6, 1_792_326_640_000_000)
+    );
+}
+
+#[test]
+fn excluded_units_never_reach_the_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let collector = Collector::start(listener);
+    let path = cursor_path("exclude");
+
+    let exclude = ExcludeRecord {
+        units: vec![String::from("noisy.service")],
+        ..ExcludeRecord::default()
+    };
+    let mut transforms: Vec<Box<dyn Transform>> = vec![Box::new(exclude)];
+    let mut source = MemorySource::from_export_file("", &fixture("units.export")).unwrap();
+    assert_eq!(deliver(&mut source, &mut transforms, port, &path), 4);
+
+    assert_eq!(
+        collector.messages(4),
+        vec!["app 1", "app 2", "app 3\nwith a second line", "app 4"]
+    );
+    assert_eq!(
+        read_cursor_state(&path).get(""),
+        MemorySource::cursor(6, 1_792_326_640_000_000)
+    );
+}
+
+#[test]
+fn exclusion_only_trusts_the_fields_journald_adds() {
+    let exclude = ExcludeRecord {
+        pids: vec![String::from("4242")],
+        ..ExcludeRecord::default()
+    };
+    let record = |fields: &[(&str, &str)]| {
+        fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<std::collections::BTreeMap<String, String>>()
+    };
+    assert!(exclude.matches(&record(&[("_PID", "4242")])));
+    // A sender can set PID= to anything, that neither hides nor drops a record
+    assert!(!exclude.matches(&record(&[("_PID", "7"), ("PID", "4242")])));
+}
+
+#[test]
+fn only_a_forked_child_excludes_its_parent() {
+    let parent = nix::unistd::getppid().to_string();
+    let settings = Settings::default();
+    assert!(!ExcludeRecord::from_settings(&settings, false)
+        .pids
+        .contains(&parent));
+    assert!(ExcludeRecord::from_settings(&settings, true)
+        .pids
+        .contains(&parent));
+}
+
+#[test]
+fn an_export_on_stdin_is_forwarded_by_the_binary() {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_journaldeliver"))
+        .args(["--dry-run", "--stdin", "--verbose", "0"])
+        .args(["--last-cursor-location", &cursor_path("stdin")])
+        // Keep any installed config files out of it
+        .args(["--configs", &cursor_path("no-config")])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let export = std::fs::read(fixture("units.export")).unwrap();
+    child.stdin.take().unwrap().write_all(&export).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let printed = String::from_utf8(output.stdout).unwrap();
+    let messages: Vec<String> = printed
+        .lines()
+        .map(|line| line.trim_start_matches("[filebeat tcp://127.0.0.1:9000] "))
+        .map(|line| serde_json::from_str::<JsonValue>(line).unwrap())
+        .map(|record| record["message"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(messages, all_messages());
+}